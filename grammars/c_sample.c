@@ -0,0 +1,52 @@
+typedef struct node { int value; struct node * next; } node;
+
+static const char * names[] = { "zero", "one", "two", "three" };
+
+enum color { RED, GREEN = 2, BLUE, };
+
+int sum_list(node * list)
+{
+    int total = 0;
+    while (list)
+    {
+        total += list->value;
+        list = list->next;
+    }
+    return total;
+}
+
+unsigned long hash(const char * s, unsigned long seed)
+{
+    unsigned long h = seed ^ 0x9E3779B9UL;
+    for (int i = 0; s[i] != '\0'; i++)
+    {
+        h = (h << 5) + h + (unsigned char)s[i];
+        if (h > 1000000 && !(h & 1)) h = h / 3 - 1;
+    }
+    return h;
+}
+
+double average(double * values, int count)
+{
+    double acc = 0.0;
+    int i;
+    if (count <= 0) return 0.0;
+    for (i = 0; i < count; ++i) acc = acc + values[i] * 1.0f;
+    switch (count)
+    {
+        case 1: return values[0];
+        default: break;
+    }
+    return acc / (double)count;
+}
+
+void swap(int * a, int * b) { int t = *a; *a = *b; *b = t; }
+
+int main(int argc, char * argv[])
+{
+    node n = { 5, 0 };
+    int x = argc > 1 ? sum_list(&n) : (int)sizeof(node);
+    do { x--; } while (x > 0 && names[x % 4][0] == 'o');
+    swap(&x, &n.value);
+    return x == 0 || hash(argv[0], 7) % 2 ? 0 : 1;
+}
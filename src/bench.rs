@@ -0,0 +1,90 @@
+// Benchmarks comparing the parsing backends against each other.
+// Run with: cargo run --release -- bench
+
+use crate::bnf::*;
+use crate::earley::*;
+use crate::lr0::*;
+
+fn bench_one(name : &str, grammar : &str, root : &str, input : &str, with_lr0 : bool)
+{
+    let cg = compile_grammar(bnf_to_grammar(grammar).unwrap());
+    let start = std::time::Instant::now();
+    let mut symbols = Interner::default();
+    let tokens = tokenize(&cg, &mut symbols, input).unwrap();
+    println!("{name}: {} tokens, tokenized in {:?}", tokens.len(), start.elapsed());
+    
+    let start = std::time::Instant::now();
+    let dfa = lr0_automaton_build(&cg, root).unwrap();
+    println!("    LR(0) automaton: {} states, built in {:?}", dfa.states.len(), start.elapsed());
+    
+    let report = |what : &str, ok : bool, elapsed : std::time::Duration|
+    {
+        let rate = tokens.len() as f64 / elapsed.as_secs_f64();
+        println!("    {what:<40} {:<5} {elapsed:>12.3?} ({rate:.0} tokens/sec)", if ok { "ok" } else { "FAIL" });
+    };
+    for (what, mode) in [("chart_fill (at prediction)", RightRecursionMode::AtPrediction), ("chart_fill (at completion)", RightRecursionMode::AtCompletion)]
+    {
+        let options = EarleyOptions { right_recursion : mode, ..<_>::default() };
+        let start = std::time::Instant::now();
+        let ok = earley_recognize_with_options(&cg, root, &tokens, &options).is_ok();
+        report(&format!("{what} recognize"), ok, start.elapsed());
+        let start = std::time::Instant::now();
+        let ok = earley_parse_with_options(&cg, root, &symbols, &tokens, &options).is_ok();
+        report(&format!("{what} parse"), ok, start.elapsed());
+    }
+    if !with_lr0 { return; }
+    let start = std::time::Instant::now();
+    let ok = lr0_recognize(&cg, &dfa, &tokens).is_ok();
+    report("LR(0) recognize", ok, start.elapsed());
+    let start = std::time::Instant::now();
+    let ok = lr0_parse(&cg, &dfa, &symbols, &tokens).is_ok();
+    report("LR(0) parse", ok, start.elapsed());
+}
+
+// Tokenize and parse lots of separate inputs with a single shared grammar, first on one thread and then on all of them.
+fn bench_parallel(name : &str, grammar : &str, root : &str, input : &str, count : usize)
+{
+    let cg = compile_grammar(bnf_to_grammar(grammar).unwrap());
+    let threads = std::thread::available_parallelism().map(|x| x.get()).unwrap_or(1);
+    println!("{name}: {count} inputs, shared between threads");
+    
+    // Each thread gets its own symbol table, since the grammar is read-only.
+    let parse_some = |count : usize|
+    {
+        let mut symbols = Interner::default();
+        for _ in 0..count
+        {
+            let tokens = tokenize(&cg, &mut symbols, input).unwrap();
+            assert!(earley_parse(&cg, root, &symbols, &tokens).is_ok());
+        }
+    };
+    let start = std::time::Instant::now();
+    parse_some(count);
+    println!("    {:<40} {:>12.3?}", "1 thread", start.elapsed());
+    
+    let start = std::time::Instant::now();
+    std::thread::scope(|scope|
+    {
+        for i in 0..threads
+        {
+            scope.spawn(move || parse_some(count / threads + if i < count % threads { 1 } else { 0 }));
+        }
+    });
+    println!("    {:<40} {:>12.3?}", format!("{threads} thread(s)"), start.elapsed());
+}
+
+pub fn run_benchmarks()
+{
+    let readme_grammar = r####"
+program ::= B B A
+A ::= "a" A | "a" | "b" | "c" | "d" | "e" | "f" | "g"
+B ::= #intentionally empty
+"####;
+    // This grammar is right-recursive, which the LR(0) backend doesn't have a fix for (it's quadratic on it),
+    //  so the LR(0) backend only gets the short input.
+    bench_one("README grammar, short", readme_grammar, "program", &"a ".repeat(2000), true);
+    bench_one("README grammar, long", readme_grammar, "program", &"a ".repeat(100000), false);
+    
+    bench_one("C grammar", include_str!("../grammars/c.bnf"), "translation_unit", &include_str!("../grammars/c_sample.c").repeat(100), true);
+    bench_parallel("C grammar, parallel", include_str!("../grammars/c.bnf"), "translation_unit", include_str!("../grammars/c_sample.c"), 200);
+}
@@ -1,6 +1,6 @@
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
-use std::rc::Rc;
-use std::cell::RefCell;
+use std::sync::Arc;
 use regex::Regex;
 
 // Rust doesn't have these functions: check if a given byte index in a string is utf-8 or not.
@@ -18,21 +18,44 @@ pub fn get_char_at_byte(s : &str, i : usize) -> char
     s[i..].chars().next().unwrap()
 }
 
+// A regex terminal. The parsers never run the regex: tokens record the ids of the regexes they match (Token::regexes).
+// id is the index of the pattern among the grammar's distinct regex terminals (the same pattern always gets the same id).
 #[derive(Debug, Clone)]
 pub struct RegexCacher {
+    pub id : usize,
     r : Regex,
-    cache : RefCell<HashMap<Rc<String>, bool>>,
 }
 
 impl RegexCacher {
-    pub fn new(r : Regex) -> RegexCacher { RegexCacher { r, cache : RefCell::new(HashMap::default()) } }
-    pub fn is_match(&self, s : &Rc<String>) -> bool
+    pub fn new(id : usize, r : Regex) -> RegexCacher { RegexCacher { id, r } }
+    pub fn regex(&self) -> &Regex { &self.r }
+}
+
+// Interned token text. Two symbols from the same Interner are equal if and only if their text is equal.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SymbolId(pub u32);
+
+// Symbol table for token text. It belongs to whoever's tokenizing, not to the grammar, so tokenizing doesn't modify the
+//  grammar, and the table doesn't grow forever in a long-running process: use one per input, or one per batch of inputs
+//  (e.g. one per thread), and drop it along with the tokens and ASTs that refer to it.
+#[derive(Debug, Default)]
+pub struct Interner {
+    ids : HashMap<String, SymbolId>,
+    texts : Vec<Arc<String>>,
+}
+
+impl Interner {
+    pub fn get(&self, s : &str) -> Option<SymbolId> { self.ids.get(s).copied() }
+    pub fn text(&self, id : SymbolId) -> &Arc<String> { &self.texts[id.0 as usize] }
+    #[allow(unused)]
+    pub fn len(&self) -> usize { self.texts.len() }
+    pub fn intern(&mut self, s : &str) -> SymbolId
     {
-        let mut cache = self.cache.borrow_mut();
-        if let Some(result) = cache.get(s) { return *result; }
-        let ret = self.r.is_match(&*s);
-        cache.insert(Rc::clone(s), ret);
-        ret
+        if let Some(id) = self.get(s) { return id; }
+        let id = SymbolId(self.texts.len().try_into().expect("More than 4 billion distinct symbols"));
+        self.texts.push(Arc::new(s.to_string()));
+        self.ids.insert(s.to_string(), id);
+        id
     }
 }
 
@@ -41,15 +64,173 @@ pub struct Grammar {
     pub points: Vec<GrammarPoint>,
     pub by_name: HashMap<String, usize>,
     
+    // Sorted, and indexed by literal id.
     pub literals: Vec<String>,
+    // Ids (sorted) of the case-insensitive literals, like i"select". Their text is lowercase.
+    pub case_insensitive: Vec<usize>,
+    // Tokenization versions of the regex terminals (only anchored at the start), indexed by regex id.
     pub regexes: Vec<Regex>,
+    // The same, for tokenize_bytes(). Byte terminals (0x7F, 0x00-0x1F, rxb%...%rxb) only match there,
+    //  so their entries in regexes are regexes that never match.
+    pub byte_regexes: Vec<regex::bytes::Regex>,
+    // Literal ids (sorted) that each regex terminal refuses to match, also indexed by regex id. Usually empty.
+    // From exclusions like identifier ::= rx%[a-z]+%rx - keywords, where keywords ::= "if" | "else" | ...
+    pub regex_exclusions: Vec<Vec<usize>>,
     
-    pub string_cache : HashMap<String, Rc<String>>,
+    // Empty unless the grammar file has @mode directives. Mode 0 is the default mode.
+    pub lexer_modes : Vec<LexerMode>,
+    // Comments, from @comment directives (only anchored at the start). Tokenizers skip them like whitespace.
+    pub comments : Vec<Regex>,
+    // Length-prefixed constructs, from @length directives: (rule, alt, pos) of each use of one -> how to check it.
+    pub lengths : HashMap<(usize, usize, usize), LengthCheck>,
+    // Completion restrictions, from @nofollow and @reject directives, by rule id.
+    pub restrictions : HashMap<usize, Restriction>,
+    // Typed values for AST leaves, from @convert directives or set_converter(), by terminal.
+    pub converters : HashMap<TermId, Converter>,
+}
+
+// What an item of a rule with @nofollow or @reject can't complete over (checked by chart_fill, like LengthCheck).
+// Mostly for scannerless grammars (see tokenize_chars()), where longest match and keyword reservation don't come for free.
+#[derive(Debug, Clone, Default)]
+pub struct Restriction {
+    // Terminals that can't come right after it, e.g. [a-z0-9_] after an identifier, for longest match.
+    pub nofollow : Vec<TermId>,
+    // Sequences of terminals that it can't be, e.g. the characters of each keyword, for keyword reservation.
+    pub reject : Vec<Vec<TermId>>,
+}
+
+// A construct whose length is given by the value of a token before it (see Token::value), like a netstring's payload.
+// chart_fill only lets the item that uses the construct advance past it if the construct is exactly that long.
+#[derive(Debug, Clone, Copy)]
+pub struct LengthCheck {
+    // How many tokens back from the start of the construct the length token is. (Everything in between is one token.)
+    pub back : usize,
+    // Whether the length counts bytes of input (from the start of the first token to the end of the last) or tokens.
+    pub bytes : bool,
+}
+
+// tokenize() keeps a stack of lexer modes, and only lexes the terminals of the mode on top of it.
+// Lexing some terminals pushes or pops a mode, e.g. a string's opening quote pushes a mode where only the string's
+//  contents, "${" and the closing quote get lexed, and "${" pushes the default mode back on top of that.
+#[derive(Debug, Clone)]
+pub struct LexerMode {
+    pub name : String,
+    pub skip_whitespace : bool,
+    pub terms : HashSet<TermId>,
+    pub actions : HashMap<TermId, ModeAction>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModeAction {
+    Push(usize),
+    Pop,
+}
+
+impl Grammar {
+    // Looks up a terminal the way it's written in the grammar: a literal ("if"), a regex (rx%[a-z]+%rx), INDENT/DEDENT/NEWLINE,
+    //  or the name of a rule that's nothing but a single terminal (like identifier ::= rx%[a-z]+%rx).
+    // This is how an external lexer (see Tokenizer) finds out which terminals its kinds of token are.
+    // A regex that the grammar uses with different exclusions (see regex_exclusions) is a different terminal each time, so
+    //  looking it up by its pattern gives None. Look those up by the name of a rule that's just one of them instead.
+    pub fn terminal(&self, name : &str) -> Option<TermId>
+    {
+        if is_bnf_literal(name)
+        {
+            return self.literal_id(&bnf_literal_text(name)).map(TermId::Lit);
+        }
+        if is_bnf_regex(name)
+        {
+            return match self.regex_terminals(name)[..]
+            {
+                [id] => Some(id),
+                _ => None,
+            };
+        }
+        if let Some(literal) = layout_literal(&self.by_name, name)
+        {
+            return self.literal_id(literal).map(TermId::Lit);
+        }
+        match &self.points[*self.by_name.get(name)?].forms[..]
+        {
+            [alt] => match &alt.matching_terms[..]
+            {
+                [MatchingTerm::TermLit(id)] => Some(TermId::Lit(*id)),
+                [MatchingTerm::TermRegex(regex)] => Some(TermId::Regex(regex.id)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+    // Every terminal for a regex written like rx%[a-z]+%rx: one for each set of exclusions that the grammar uses it with.
+    pub fn regex_terminals(&self, name : &str) -> Vec<TermId>
+    {
+        if !is_bnf_regex(name) { return Vec::new(); }
+        let pattern = format!("\\A{}", &name[3..name.len() - 3]);
+        self.regexes.iter().enumerate().filter(|(_, r)| r.as_str() == pattern).map(|(id, _)| TermId::Regex(id)).collect()
+    }
+    // Gives the AST leaves of a terminal a typed value (see Converter), replacing any @convert for it. terminal is named the way
+    //  terminal() takes it, or it's a rule whose every alternation is a single terminal (like boolean ::= "true" | "false").
+    pub fn set_converter(&mut self, terminal : &str, converter : Converter) -> Result<(), String>
+    {
+        if self.regex_terminals(terminal).len() > 1
+        {
+            return Err(format!("Can't convert {terminal}: the grammar uses it with different exclusions, so convert a rule that's just one of them instead"));
+        }
+        let terms = match self.terminal(terminal)
+        {
+            Some(term) => Some(vec!(term)),
+            None => self.by_name.get(terminal).and_then(|&id| self.single_terminals(id)),
+        };
+        let terms : Vec<TermId> = terms.ok_or_else(|| format!("Can't convert {terminal}: it isn't a terminal, or a rule made of single terminals"))?;
+        for term in terms { self.converters.insert(term, converter.clone()); }
+        Ok(())
+    }
+    // The terminals of a rule whose every alternation is a single terminal (like boolean ::= "true" | "false"), or None.
+    pub fn single_terminals(&self, rule : usize) -> Option<Vec<TermId>>
+    {
+        self.points[rule].forms.iter().map(|alt| match &alt.matching_terms[..]
+        {
+            [MatchingTerm::TermLit(id)] => Some(TermId::Lit(*id)),
+            [MatchingTerm::TermRegex(regex)] => Some(TermId::Regex(regex.id)),
+            _ => None,
+        }).collect()
+    }
+    // Which literal has exactly this text (or this text, lowercased, for case-insensitive literals), if any.
+    pub fn literal_id(&self, text : &str) -> Option<usize>
+    {
+        let find = |text : &str| self.literals.binary_search_by(|literal| literal.as_str().cmp(text)).ok();
+        let id = find(text);
+        if id.is_some() || self.case_insensitive.is_empty() { return id; }
+        find(&text.to_lowercase()).filter(|id| self.is_case_insensitive(*id))
+    }
+    pub fn is_case_insensitive(&self, id : usize) -> bool
+    {
+        self.case_insensitive.binary_search(&id).is_ok()
+    }
+    // The literal as a regex pattern, for the tokenizers.
+    pub fn literal_pattern(&self, id : usize) -> String
+    {
+        let pattern = regex::escape(&self.literals[id]);
+        if self.is_case_insensitive(id) { format!("(?i:{pattern})") } else { pattern }
+    }
+    // Does the literal match at the start of s?
+    pub fn literal_matches_at(&self, id : usize, s : &[u8]) -> bool
+    {
+        let text = &self.literals[id];
+        let Some(prefix) = s.get(..text.len()) else { return false };
+        if !self.is_case_insensitive(id) { return prefix == text.as_bytes(); }
+        std::str::from_utf8(prefix).is_ok_and(|prefix| prefix.to_lowercase() == *text)
+    }
+    // Does this regex terminal refuse tokens that are this literal? (See regex_exclusions.)
+    pub fn excludes(&self, regex_id : usize, lit : Option<usize>) -> bool
+    {
+        lit.is_some_and(|lit| self.regex_exclusions[regex_id].binary_search(&lit).is_ok())
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct GrammarPoint {
-    pub name: Rc<String>,
+    pub name: Arc<String>,
     pub id: usize,
     pub forms: Vec<Alternation>,
 }
@@ -62,19 +243,149 @@ pub struct Alternation {
 #[derive(Debug, Clone)]
 pub enum MatchingTerm {
     Rule(usize),
-    TermLit(Rc<String>),
+    // Literal id, i.e. index into Grammar::literals.
+    TermLit(usize),
     TermRegex(RegexCacher),
 }
 
-pub fn string_cache_lookup(string_cache : &mut HashMap<String, Rc<String>>, s : &str) -> Rc<String>
+// Length of the "..." literal at the start of rest, including the quotes. None if it's unterminated or empty.
+fn bnf_literal_len(rest : &str) -> Option<usize>
+{
+    let mut len = 1;
+    let mut in_escape = false;
+    let mut found_exit = false;
+    while len < rest.len()
+    {
+        let c = get_char_at_byte(rest, len);
+        len += c.len_utf8();
+        if !in_escape && c == '\\'
+        {
+            in_escape = true;
+            continue;
+        }
+        if !in_escape && c == '"' { found_exit = true; break; }
+        in_escape = false;
+    }
+    if !found_exit || len == 2 { return None; }
+    Some(len)
+}
+// Same, for rx%...%rx regexes (and rxb%...%rxb byte regexes).
+fn bnf_regex_len(rest : &str) -> Option<usize>
+{
+    if rest.starts_with("rxb%") { return rest[4..].find("%rxb").map(|end| end + 8); }
+    rest[3..].find("%rx").map(|end| end + 6)
+}
+// Same, for [...] character classes (which can have classes in them, like [[:alpha:]_]).
+fn bnf_class_len(rest : &str) -> Option<usize>
+{
+    let bytes = rest.as_bytes();
+    let mut depth = 0;
+    let mut i = 0;
+    while i < bytes.len()
+    {
+        match bytes[i]
+        {
+            b'\\' => i += 1,
+            b'[' =>
+            {
+                depth += 1;
+                // A ] right at the start of a class is part of it.
+                if bytes.get(i + 1) == Some(&b'^') { i += 1; }
+                if bytes.get(i + 1) == Some(&b']') { i += 1; }
+            }
+            b']' =>
+            {
+                depth -= 1;
+                if depth == 0 { return Some(i + 1); }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+// The text of a "..." literal term, with escapes resolved.
+// Case-insensitive literals (i"select") are lowercased.
+fn bnf_literal_text(term_str : &str) -> String
+{
+    let quoted = term_str.strip_prefix('i').unwrap_or(term_str);
+    let inner = &quoted[1..quoted.len() - 1];
+    // bnf_parse() already rejected bad escapes, so any that are left are in directives. Leave those alone.
+    let literal = bnf_unescape(inner).unwrap_or_else(|_| inner.to_string());
+    if is_bnf_ci_literal(term_str) { literal.to_lowercase() } else { literal }
+}
+// Resolves \n, \r, \t, \0, \\, \", \', \xHH (up to \x7F) and \u{HHHHHH}.
+fn bnf_unescape(s : &str) -> Result<String, String>
+{
+    let mut ret = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next()
+    {
+        if c != '\\' { ret.push(c); continue; }
+        let c = match chars.next()
+        {
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('0') => '\0',
+            Some(c @ ('\\' | '"' | '\'')) => c,
+            Some('x') =>
+            {
+                let hex = chars.by_ref().take(2).collect::<String>();
+                u8::from_str_radix(&hex, 16).ok().filter(|b| hex.len() == 2 && *b < 0x80).map(char::from)
+                    .ok_or_else(|| format!("Bad escape \\x{hex} (it takes two hex digits, up to 7F)"))?
+            }
+            Some('u') =>
+            {
+                let rest = chars.as_str();
+                let hex = rest.strip_prefix('{').and_then(|rest| rest.split_once('}')).map(|(hex, _)| hex).unwrap_or("");
+                let c = u32::from_str_radix(hex, 16).ok().filter(|_| hex.len() <= 6).and_then(char::from_u32)
+                    .ok_or_else(|| format!("Bad escape \\u{{{hex}}}"))?;
+                chars = rest[hex.len() + 2..].chars();
+                c
+            }
+            Some(c) => return Err(format!("Unknown escape \\{c}")),
+            None => return Err(format!("Unterminated escape")),
+        };
+        ret.push(c);
+    }
+    Ok(ret)
+}
+// Terminals for indentation-sensitive grammars, which grammars refer to by name (e.g. block ::= NEWLINE INDENT stmts DEDENT).
+// tokenize() only produces them with TokenizeOptions::indentation. They're literals with text that no lexer ever matches.
+pub const LAYOUT_TERMINALS : [(&str, &str); 3] = [("INDENT", "<INDENT>"), ("DEDENT", "<DEDENT>"), ("NEWLINE", "<NEWLINE>")];
+pub fn is_layout_literal(text : &str) -> bool
+{
+    LAYOUT_TERMINALS.iter().any(|(_, lit)| *lit == text)
+}
+
+fn is_bnf_literal(term_str : &str) -> bool
+{
+    let quoted = term_str.strip_prefix('i').unwrap_or(term_str);
+    quoted.starts_with('"') && quoted.ends_with('"') && quoted.len() >= 2
+}
+fn is_bnf_ci_literal(term_str : &str) -> bool { term_str.starts_with("i\"") && is_bnf_literal(term_str) }
+fn is_bnf_regex(term_str : &str) -> bool { term_str.starts_with("rx%") && term_str.ends_with("%rx") && term_str.len() >= 6 }
+// What byte terminals' entries in Grammar::regexes are.
+const NEVER_MATCH_PATTERN : &str = "\\A[^\\s\\S]";
+// Byte terminals as regex::bytes patterns (for regexes with Unicode turned off, so that \xFF is the byte 0xFF, not ÿ):
+//  0x7F is a single byte, 0x00-0x1F is a range of bytes, and rxb%...%rxb is a byte regex.
+fn bnf_byte_pattern(term_str : &str) -> Option<String>
 {
-    if let Some(s) = string_cache.get(s)
+    let byte = |s : &str| if s.len() == 4 && s.starts_with("0x") { u8::from_str_radix(&s[2..], 16).ok() } else { None };
+    if let Some(b) = byte(term_str)
+    {
+        return Some(format!("\\x{b:02X}"));
+    }
+    if let Some((lo, hi)) = term_str.split_once('-') && let (Some(lo), Some(hi)) = (byte(lo), byte(hi))
+    {
+        return Some(format!("[\\x{lo:02X}-\\x{hi:02X}]"));
+    }
+    if term_str.starts_with("rxb%") && term_str.ends_with("%rxb") && term_str.len() >= 8
     {
-        return Rc::clone(s);
+        return Some(term_str[4..term_str.len() - 4].to_string());
     }
-    let rc = Rc::new(s.to_string());
-    string_cache.insert(s.to_string(), Rc::clone(&rc));
-    rc
+    None
 }
 
 pub fn bnf_parse(input: &str) -> Result<Vec<(String, Vec<Vec<String>>)>, String>
@@ -85,6 +396,9 @@ pub fn bnf_parse(input: &str) -> Result<Vec<(String, Vec<Vec<String>>)>, String>
     {
         linenum += 1; // user-facing line numbers are 1-indexed
         
+        // Directives (like @mode) are handled by their own parsers.
+        if rest.trim_start().starts_with("@") { continue; }
+        
         let mut name : Option<String> = None;
         let mut found_separator = false;
         
@@ -102,38 +416,32 @@ pub fn bnf_parse(input: &str) -> Result<Vec<(String, Vec<Vec<String>>)>, String>
             {
                 break;
             }
-            // literal
-            else if rest.starts_with("\"")
+            // literal (i"..." is case-insensitive)
+            else if rest.starts_with("\"") || rest.starts_with("i\"")
             {
                 if !found_separator { return Err(format!("Missing ::= on line {linenum}")); }
-                let mut len = 1;
-                let mut in_escape = false;
-                let mut found_exit = false;
-                while len < rest.len()
-                {
-                    let c = get_char_at_byte(rest, len);
-                    len += c.len_utf8();
-                    if !in_escape && c == '\\'
-                    {
-                        in_escape = true;
-                        continue;
-                    }
-                    if !in_escape && c == '"' { found_exit = true; break; }
-                    in_escape = false;
-                }
-                if !found_exit || len == 2 { return Err(format!("Broken literal text rule on line {linenum}")); }
+                let prefix = if rest.starts_with('i') { 1 } else { 0 };
+                let len = bnf_literal_len(&rest[prefix..]).ok_or_else(|| format!("Broken literal text rule on line {linenum}"))? + prefix;
+                bnf_unescape(&rest[prefix + 1..len - 1]).map_err(|e| format!("{e} on line {linenum}"))?;
                 current.push(rest[..len].to_string());
                 rest = &rest[len..];
             }
             // regex
-            else if rest.starts_with("rx%")
+            else if rest.starts_with("rx%") || rest.starts_with("rxb%")
             {
                 if !found_separator { return Err(format!("Missing ::= on line {linenum}")); }
-                let end = rest[3..].find("%rx").expect(&format!("Unterminated regex on line {linenum}"));
-                let len = end + 6;
+                let len = bnf_regex_len(rest).expect(&format!("Unterminated regex on line {linenum}"));
                 current.push(rest[..len].to_string());
                 rest = &rest[len..];
             }
+            // character class, which is shorthand for a regex
+            else if rest.starts_with("[")
+            {
+                if !found_separator { return Err(format!("Missing ::= on line {linenum}")); }
+                let len = bnf_class_len(rest).ok_or_else(|| format!("Unterminated character class on line {linenum}"))?;
+                current.push(format!("rx%{}%rx", &rest[..len]));
+                rest = &rest[len..];
+            }
             // split
             else if rest.starts_with("::=")
             {
@@ -157,7 +465,7 @@ pub fn bnf_parse(input: &str) -> Result<Vec<(String, Vec<Vec<String>>)>, String>
                 for (i, ch) in rest.char_indices()
                 {
                     if ch.is_whitespace() || ch == '|' || ch == '"' || ch == '#'
-                        || rest[i..].starts_with("::=") || rest[i..].starts_with("rx%")
+                        || rest[i..].starts_with("::=") || rest[i..].starts_with("rx%") || rest[i..].starts_with("rxb%")
                     {
                         end = i;
                         break;
@@ -183,232 +491,2149 @@ pub fn bnf_parse(input: &str) -> Result<Vec<(String, Vec<Vec<String>>)>, String>
     Ok(rules)
 }
 
-pub fn grammar_convert(input: &Vec<(String, Vec<Vec<String>>)>) -> Result<Grammar, String>
+// Every directive that there is. Lines that start with anything else after an @ are errors.
+const DIRECTIVES : [&str; 9] = ["@comment", "@length", "@convert", "@mode", "@scannerless", "@layout", "@lexical", "@nofollow", "@reject"];
+
+// The lines that bnf_parse() skips over because they're directives, as (line number, directive, rest of the line).
+// The directive parsers below each pick their own directives out of these.
+fn bnf_directives(input : &str) -> Result<Vec<(usize, &str, &str)>, String>
 {
-    let mut by_name = HashMap::new();
-    for (index, (name, _)) in input.iter().enumerate()
+    let mut directives = Vec::new();
+    for (mut linenum, line) in input.lines().enumerate()
     {
-        if by_name.insert(name.clone(), index).is_some()
+        linenum += 1;
+        
+        let line = line.trim();
+        if !line.starts_with("@") { continue; }
+        let (directive, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        if !DIRECTIVES.contains(&directive)
         {
-            return Err(format!("Duplicate rule {name}; use alternations (e.g. x ::= a | b), not additional definitions (like x ::= a [...] x ::= b)"));
+            return Err(format!("Unknown directive {directive} on line {linenum}"));
         }
+        directives.push((linenum, directive, rest.trim_start()));
     }
-    
-    let mut string_cache = HashMap::new();
-    let mut points = Vec::new();
-    let mut literals = HashSet::new();
-    let mut regexes = Vec::new();
-    for (index, (name, raw_forms)) in input.iter().enumerate()
+    Ok(directives)
+}
+
+// Parses the @comment directives that bnf_parse() skips over, e.g. `@comment rx%//[^\n]*%rx`, and returns their patterns.
+pub fn bnf_parse_comments(input : &str) -> Result<Vec<String>, String>
+{
+    let mut comments = Vec::new();
+    for (linenum, directive, rest) in bnf_directives(input)?
     {
-        let mut forms = Vec::new();
-        
-        for raw_alt in raw_forms
-        {
-            let mut matching_terms = Vec::new();
-            
-            for term_str in raw_alt
-            {
-                if term_str.starts_with('"') && term_str.ends_with('"') && term_str.len() >= 2
-                {
-                    let mut literal = term_str[1..term_str.len() - 1].to_string();
-                    literal = literal.replace("\\\"", "\"");
-                    literal = literal.replace("\\\\", "\\");
-                    matching_terms.push(MatchingTerm::TermLit(string_cache_lookup(&mut string_cache, &literal)));
-                    literals.insert(literal.clone());
-                    continue;
-                }
-                if term_str.starts_with("rx%") && term_str.ends_with("%rx") && term_str.len() >= 6
-                {
-                    let pattern = &term_str[3..term_str.len() - 3];
-                    let pattern_all = format!("\\A{pattern}\\z"); // full match (for parsing)
-                    let pattern = format!("\\A{pattern}"); // at start (for tokenization)
-                    let re = Regex::new(&pattern).map_err(|e| format!("Invalid regex '{}': {}", pattern, e))?;
-                    let re2 = Regex::new(&pattern_all).map_err(|e| format!("Invalid regex '{}': {}", pattern_all, e))?;
-                    regexes.push(re.clone());
-                    matching_terms.push(MatchingTerm::TermRegex(RegexCacher::new(re2)));
-                    continue;
-                }
-                let id = by_name.get(term_str).ok_or_else(|| format!("Not a defined grammar rule: '{}'", term_str))?;
-                matching_terms.push(MatchingTerm::Rule(*id));
-            }
-            if matching_terms.len() > 60000
-            {
-                return Err(format!("More than 60k items in an alternation of {name}. Factor them out, dummy!"));
-            }
-            forms.push(Alternation { matching_terms });
-        }
-        if forms.len() > 60000
+        if directive != "@comment" { continue; }
+        if !rest.starts_with("rx%") || bnf_regex_len(rest) != Some(rest.len())
         {
-            return Err(format!("More than 60k alternations in {name}. Factor them out, dummy!"));
+            return Err(format!("Expected a single rx%...%rx regex after @comment on line {linenum}"));
         }
-        points.push(GrammarPoint
-        {
-            name: Rc::new(name.clone()),
-            id: index,
-            forms,
-        });
+        comments.push(rest[3..rest.len() - 3].to_string());
     }
-    if points.len() > 4000000000
+    Ok(comments)
+}
+
+// Parses @length directives, which look like this:
+//   @length payload digits
+//   @length payload digits bytes
+// That is, wherever payload gets used, it's as many tokens long (or bytes, with "bytes") as the value of the digits
+//  term before it. digits can be a rule name or a terminal. Returns (name, length term, whether it counts bytes).
+pub fn bnf_parse_lengths(input : &str) -> Result<Vec<(String, String, bool)>, String>
+{
+    let mut lengths = Vec::new();
+    for (linenum, directive, rest) in bnf_directives(input)?
     {
-        return Err(format!("More than 4 billion grammar terms in grammar. What are you doing??? STOP!!!!! (╯°□°）╯︵ ┻━┻"));
+        if directive != "@length" { continue; }
+        let (name, rest) = rest.split_once(char::is_whitespace).ok_or_else(|| format!("Missing length term on line {linenum}"))?;
+        let rest = rest.trim_start();
+        let len = if rest.starts_with('"') { bnf_literal_len(rest) }
+            else if rest.starts_with("i\"") { bnf_literal_len(&rest[1..]).map(|len| len + 1) }
+            else if rest.starts_with("rx%") || rest.starts_with("rxb%") { bnf_regex_len(rest) }
+            else { Some(rest.find(char::is_whitespace).unwrap_or(rest.len())) };
+        let len = len.ok_or_else(|| format!("Broken length term on line {linenum}"))?;
+        let bytes = match rest[len..].trim()
+        {
+            "" => false,
+            "bytes" => true,
+            _ => return Err(format!("Broken @length directive on line {linenum}")),
+        };
+        lengths.push((name.to_string(), rest[..len].to_string(), bytes));
     }
-    
-    let mut literals = literals.into_iter().collect::<Vec<_>>();
-    literals.sort();
-    Ok(Grammar { points, by_name, literals, regexes, string_cache })
+    Ok(lengths)
 }
 
-pub fn bnf_to_grammar(s : &str) -> Result<Grammar, String>
+// Parses @convert directives, which look like this:
+//   @convert number int
+//   @convert rx%[0-9]+\.[0-9]*%rx float
+//   @convert string string
+//   @convert boolean bool
+// That is, the AST leaves of that terminal (or of the terminals of that rule) get their text converted (see Converter).
+pub fn bnf_parse_converters(input : &str) -> Result<Vec<(String, Converter)>, String>
 {
-    grammar_convert(&bnf_parse(s)?)
+    let mut converters = Vec::new();
+    for (linenum, directive, rest) in bnf_directives(input)?
+    {
+        if directive != "@convert" { continue; }
+        let [name, kind] = &bnf_directive_terms(rest, linenum)?[..] else
+        {
+            return Err(format!("Broken @convert directive on line {linenum}"));
+        };
+        let converter = match kind.as_str()
+        {
+            "int" => Converter::Int,
+            "float" => Converter::Float,
+            "string" => Converter::String,
+            "bool" => Converter::Bool,
+            _ => return Err(format!("Unknown converter {kind} on line {linenum} (it can be int, float, string or bool)")),
+        };
+        converters.push((name.clone(), converter));
+    }
+    Ok(converters)
 }
 
+// Directives for scannerless grammars, which get parsed one character at a time (see tokenize_chars()):
+//   @scannerless
+//   @layout _
+//   @lexical identifier number
+//   @nofollow identifier [a-zA-Z0-9_]
+//   @reject identifier keyword
+// @layout names the rule that goes between the terms of every rule except the @lexical ones.
+// @nofollow and @reject work in ordinary grammars too: the rule can't be followed by any of those terminals,
+//  or be any of those literals (or any of the literals of those rules, e.g. keyword ::= "if" | "else").
+// They can also restrict a literal, but only in scannerless grammars, where literals turn into rules.
 #[derive(Debug, Clone, Default)]
-pub struct Token {
-    pub text : Rc<String>,
+pub struct ScannerlessDirectives {
+    pub enabled : bool,
+    pub layout : Option<String>,
+    pub lexical : HashSet<String>,
+    // (rule, terms)
+    pub nofollow : Vec<(String, Vec<String>)>,
+    pub reject : Vec<(String, Vec<String>)>,
 }
 
-// Sort literals from grammar by length and combine them into a single match-longest regex.
-pub fn build_literal_regex(g : &Grammar) -> Regex
+// Splits the rest of a directive line into terms, the same way that bnf_parse() would.
+fn bnf_directive_terms(mut rest : &str, linenum : usize) -> Result<Vec<String>, String>
 {
-    let mut text_token_regex_s = "^(".to_string();
-    
-    let mut lits = g.literals.clone();
-    lits.sort_by(|a, b| b.len().cmp(&a.len()));
-    for text in lits.iter()
+    let mut terms = Vec::new();
+    loop
     {
-        let s2 = regex::escape(&text);
-        text_token_regex_s += &s2;
-        text_token_regex_s += "|";
+        rest = rest.trim_start();
+        if rest.is_empty() { break; }
+        let len = if rest.starts_with('"') { bnf_literal_len(rest) }
+            else if rest.starts_with("i\"") { bnf_literal_len(&rest[1..]).map(|len| len + 1) }
+            else if rest.starts_with("rx%") || rest.starts_with("rxb%") { bnf_regex_len(rest) }
+            else if rest.starts_with('[') { bnf_class_len(rest) }
+            else { Some(rest.find(char::is_whitespace).unwrap_or(rest.len())) };
+        let len = len.ok_or_else(|| format!("Broken term on line {linenum}"))?;
+        terms.push(if rest.starts_with('[') { format!("rx%{}%rx", &rest[..len]) } else { rest[..len].to_string() });
+        rest = &rest[len..];
     }
-    text_token_regex_s.pop();
-    text_token_regex_s += ")";
-    let text_token_regex = Regex::new(&text_token_regex_s).unwrap();
-    text_token_regex
+    Ok(terms)
 }
 
-pub fn tokenize(g : &mut Grammar, mut s : &str) -> Result<Vec<Token>, String>
+pub fn bnf_parse_scannerless(input : &str) -> Result<ScannerlessDirectives, String>
 {
-    let s_orig = s;
-    let mut tokens = vec!();
-    
-    let all_literals_regex = build_literal_regex(g);
-    
-    let mut make_token = |s : &str| Token { text : string_cache_lookup(&mut g.string_cache, s) } ;
-    
-    for text in g.literals.iter()
-    {
-        make_token(text);
-    }
-    
-    while !s.is_empty()
+    let mut directives = ScannerlessDirectives::default();
+    for (linenum, directive, rest) in bnf_directives(input)?
     {
-        if get_char_at_byte(s, 0).is_whitespace()
-        {
-            while !s.is_empty() && get_char_at_byte(s, 0).is_whitespace()
-            {
-                s = &s[get_char_at_byte(s, 0).len_utf8()..];
-            }
-            if s.is_empty() { break; }
-        }
-        
-        let mut longest = 0;
-        for r in &g.regexes
+        if !["@scannerless", "@layout", "@lexical", "@nofollow", "@reject"].contains(&directive) { continue; }
+        let mut terms = bnf_directive_terms(rest, linenum)?;
+        match directive
         {
-            if let Some(loc) = r.find(s).map(|x| x.len())
+            "@scannerless" if terms.is_empty() => directives.enabled = true,
+            "@layout" if terms.len() == 1 => directives.layout = terms.pop(),
+            "@lexical" => directives.lexical.extend(terms),
+            "@nofollow" | "@reject" if terms.len() >= 2 =>
             {
-                longest = longest.max(loc);
+                let name = terms.remove(0);
+                if directive == "@nofollow" { directives.nofollow.push((name, terms)); } else { directives.reject.push((name, terms)); }
             }
+            _ => return Err(format!("Broken {directive} directive on line {linenum}")),
         }
-        if let Some(loc) = all_literals_regex.find(s).map(|x| x.len())
-        {
-            longest = longest.max(loc);
-        }
-        if longest == 0
-        {
-            return Err(format!("Failed to tokenize at index {}", s_orig.len()-s.len()));
-        }
-        
-        tokens.push(make_token(&s[..longest]));
-        s = &s[longest..];
     }
-    Ok(tokens)
+    if !directives.enabled && (directives.layout.is_some() || !directives.lexical.is_empty())
+    {
+        return Err(format!("@layout and @lexical only work in @scannerless grammars"));
+    }
+    Ok(directives)
 }
 
-pub fn find_nullables(g : &Grammar) -> HashSet<(usize, usize)>
+// Rewrites a scannerless grammar into one that tokenize_chars()'s tokens can be parsed with: literals longer than
+//  one character become rules with a literal for each character (named like 'if'), and the layout rule goes between
+//  every two terms of every rule that isn't lexical.
+// Also returns the @nofollow and @reject directives in terms of the rewritten grammar, as (rule, whether it's @reject,
+//  forbidden terminal or sequence of terminals), for grammar_set_restrictions().
+pub fn bnf_apply_directives(mut rules : Vec<(String, Vec<Vec<String>>)>, directives : &ScannerlessDirectives) -> Result<(Vec<(String, Vec<Vec<String>>)>, BnfRestrictions), String>
 {
-    // Following from: https://cs.stackexchange.com/questions/164696/
+    // Case-insensitive literals turn into case-insensitive characters, in rules named like i'select'. Those are regexes,
+    //  not i"s" literals, since a character token can only be one literal, and "set" needs "s" to be case-sensitive.
+    let prefix = |term : &str| if is_bnf_ci_literal(term) { "i" } else { "" };
+    let char_literal = |prefix : &str, c : char|
+    {
+        let text = c.to_string();
+        if prefix == "i" && (c.to_lowercase().ne([c]) || c.to_uppercase().ne([c])) { return format!("rx%(?i:{})%rx", regex::escape(&text)); }
+        format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+    };
+    let literal_rule = |term : &str| format!("{}'{}'", prefix(term), bnf_literal_text(term));
+    // The terms that a literal turns into, in this grammar.
+    let split = |term : &str| -> Vec<String>
+    {
+        if !directives.enabled || !is_bnf_literal(term) { return vec!(term.to_string()); }
+        bnf_literal_text(term).chars().map(|c| char_literal(prefix(term), c)).collect()
+    };
+    let mut literal_rule_names = HashSet::new();
     
-    // Building the bipartite graph:
-    let mut rhs_to_lhs = HashMap::<_, HashSet<_>>::new();
-    let mut lhs_to_rhs = HashMap::<_, HashSet<_>>::new();
-    for rule in &g.points
+    if directives.enabled
     {
-        for (alt_i, alt) in rule.forms.iter().enumerate()
+        let mut literal_rules = Vec::new();
+        for (name, alts) in rules.iter_mut()
         {
-            let lhs = (rule.id, alt_i);
-            for item in &alt.matching_terms
+            let lexical = directives.lexical.contains(name) || directives.layout.as_ref() == Some(name);
+            for alt in alts.iter_mut()
             {
-                if let MatchingTerm::Rule(child) = item
+                let mut terms = Vec::new();
+                for term in alt.iter()
                 {
-                    for j in 0..g.points[*child].forms.len()
+                    let mut term = term.clone();
+                    if is_bnf_literal(&term) && bnf_literal_text(&term).chars().count() > 1
                     {
-                        let rhs = (*child, j);
-                        
-                        if !rhs_to_lhs.contains_key(&rhs)
-                        {
-                            rhs_to_lhs.insert(rhs, HashSet::default());
-                        }
-                        rhs_to_lhs.get_mut(&rhs).unwrap().insert(lhs);
-                        
-                        if !lhs_to_rhs.contains_key(&lhs)
-                        {
-                            lhs_to_rhs.insert(lhs, HashSet::default());
-                        }
-                        lhs_to_rhs.get_mut(&lhs).unwrap().insert(rhs);
+                        let name = literal_rule(&term);
+                        if literal_rule_names.insert(name.clone()) { literal_rules.push((name.clone(), vec!(split(&term)))); }
+                        term = name;
+                    }
+                    else if is_bnf_ci_literal(&term) && bnf_literal_text(&term).chars().count() == 1 { term = split(&term).remove(0); }
+                    if let Some(layout) = &directives.layout && !lexical && let Some(prev) = terms.last()
+                        && prev != layout && term != *layout
+                    {
+                        terms.push(layout.clone());
                     }
+                    terms.push(term);
                 }
+                *alt = terms;
             }
         }
+        rules.extend(literal_rules);
     }
     
-    let mut nullable = HashSet::new();
-    let mut worklist = Vec::new();
-    // Initial population
-    for rule in &g.points
+    // Restriction targets can be literals, for the rules that literals turn into.
+    let target = |directive : &str, name : &str| match is_bnf_literal(name)
     {
-        for (alt_i, alt) in rule.forms.iter().enumerate()
+        true if literal_rule_names.contains(&literal_rule(name)) => Ok(literal_rule(name)),
+        true => Err(format!("{directive} {name}: only literals that @scannerless turns into rules can be restricted")),
+        false => Ok(name.to_string()),
+    };
+    let mut restrictions = Vec::new();
+    for (name, terms) in &directives.nofollow
+    {
+        let mut alts = Vec::new();
+        for term in terms
         {
-            if alt.matching_terms.len() == 0
+            let mut split = split(term);
+            if split.len() != 1 || !(is_bnf_literal(term) || is_bnf_regex(term) || bnf_byte_pattern(term).is_some())
             {
-                nullable.insert((rule.id, alt_i));
-                worklist.push((rule.id, alt_i));
+                return Err(format!("@nofollow {name}: {term} isn't a single terminal"));
             }
+            alts.push(vec!(split.remove(0)));
         }
+        restrictions.push((target("@nofollow", name)?, false, alts));
     }
-    
-    // Parent scanning
-    while let Some(child) = worklist.pop()
+    for (name, terms) in &directives.reject
     {
-        let parents = rhs_to_lhs.get(&child).cloned();
-        if let Some(parents) = parents
+        let mut alts = Vec::new();
+        for term in terms
         {
-            for parent in parents
+            if is_bnf_literal(term) || is_bnf_regex(term) || bnf_byte_pattern(term).is_some()
             {
-                if rhs_to_lhs.get(&child).unwrap().contains(&parent)
+                alts.push(split(term));
+                continue;
+            }
+            let Some((_, rule_alts)) = rules.iter().find(|(rule_name, _)| rule_name == term) else
+            {
+                return Err(format!("@reject {name}: {term} isn't a terminal or a rule"));
+            };
+            for alt in rule_alts
+            {
+                match &alt[..]
                 {
-                    rhs_to_lhs.get_mut(&child).unwrap().remove(&parent);
-                    lhs_to_rhs.get_mut(&parent).unwrap().remove(&child);
-                    // if parent's right set is now empty, this parent is now known to be nullable
-                    if lhs_to_rhs.get_mut(&parent).unwrap().is_empty()
-                    {
-                        nullable.insert(parent);
-                        worklist.push(parent);
-                    }
+                    [literal] if is_bnf_literal(literal) => alts.push(split(literal)),
+                    // Already split into its characters.
+                    [literal] if literal_rule_names.contains(literal) =>
+                        alts.push(rules.iter().find(|(rule_name, _)| rule_name == literal).unwrap().1[0].clone()),
+                    _ => return Err(format!("@reject {name}: every alternation of {term} has to be a single literal")),
                 }
             }
         }
+        restrictions.push((target("@reject", name)?, true, alts));
+    }
+    Ok((rules, restrictions))
+}
+
+// @nofollow and @reject directives, from bnf_apply_directives(): (rule, whether it's @reject, forbidden terminals or sequences).
+pub type BnfRestrictions = Vec<(String, bool, Vec<Vec<String>>)>;
+
+// The terminals that the restrictions mention, for grammar_convert_with_terminals(), since some of them might not be used anywhere else.
+pub fn bnf_restriction_terminals(restrictions : &BnfRestrictions) -> Vec<String>
+{
+    restrictions.iter().flat_map(|(_, _, sequences)| sequences.iter().flatten()).cloned().collect()
+}
+
+// Turns the output of bnf_apply_directives() into Grammar::restrictions. term_ids are the ids of
+//  bnf_restriction_terminals(restrictions), from grammar_convert_with_terminals().
+pub fn grammar_set_restrictions(g : &mut Grammar, input : &BnfRestrictions, term_ids : &[TermId]) -> Result<(), String>
+{
+    let mut term_ids = term_ids.iter().copied();
+    let mut restrictions = HashMap::<usize, Restriction>::new();
+    for (name, is_reject, sequences) in input
+    {
+        let id = *g.by_name.get(name).ok_or_else(|| format!("{} for undefined rule {name}", if *is_reject { "@reject" } else { "@nofollow" }))?;
+        let restriction = restrictions.entry(id).or_default();
+        for sequence in sequences
+        {
+            let terms = term_ids.by_ref().take(sequence.len()).collect::<Vec<_>>();
+            if *is_reject { restriction.reject.push(terms); } else { restriction.nofollow.extend(terms); }
+        }
     }
+    g.restrictions = restrictions;
+    Ok(())
+}
+
+// Parses the @mode directives that bnf_parse() skips over. They look like this:
+//   @mode string noskip ::= rx%[^"$]+%rx | "${" -> push default | "\"" -> pop
+// Each alternation is a single terminal, optionally followed by what lexing it does to the mode stack.
+// "noskip" means that whitespace isn't skipped between tokens in that mode.
+// Returns (mode name, whether whitespace gets skipped, [(terminal, [action words])]).
+pub fn bnf_parse_lexer_modes(input : &str) -> Result<Vec<(String, bool, Vec<(String, Vec<String>)>)>, String>
+{
+    let mut modes = Vec::new();
     
-    nullable
+    for (linenum, directive, line) in bnf_directives(input)?
+    {
+        if directive != "@mode" { continue; }
+        let (header, mut rest) = line.split_once("::=").ok_or_else(|| format!("Missing ::= on line {linenum}"))?;
+        let (name, skip_whitespace) = match header.split_whitespace().collect::<Vec<_>>()[..]
+        {
+            [name] => (name, true),
+            [name, "noskip"] => (name, false),
+            _ => return Err(format!("Broken @mode header on line {linenum}")),
+        };
+        
+        let mut terms = Vec::new();
+        let mut current : Option<(String, Vec<String>)> = None;
+        loop
+        {
+            rest = rest.trim_start();
+            if rest.is_empty() || rest.starts_with("#") { break; }
+            if rest.starts_with("|")
+            {
+                terms.push(current.take().ok_or_else(|| format!("Empty alternation on line {linenum}"))?);
+                rest = &rest[1..];
+                continue;
+            }
+            if rest.starts_with("\"") || rest.starts_with("i\"") || rest.starts_with("rx%")
+            {
+                if current.is_some() { return Err(format!("More than one terminal in an alternation on line {linenum}")); }
+                let len = if rest.starts_with("\"") { bnf_literal_len(rest) }
+                    else if rest.starts_with("i\"") { bnf_literal_len(&rest[1..]).map(|len| len + 1) }
+                    else { bnf_regex_len(rest) };
+                let len = len.ok_or_else(|| format!("Broken terminal on line {linenum}"))?;
+                current = Some((rest[..len].to_string(), Vec::new()));
+                rest = &rest[len..];
+                continue;
+            }
+            let len = rest.find(|c : char| c.is_whitespace() || c == '|').unwrap_or(rest.len());
+            let (_, words) = current.as_mut().ok_or_else(|| format!("Expected a terminal on line {linenum}"))?;
+            words.push(rest[..len].to_string());
+            rest = &rest[len..];
+        }
+        terms.push(current.ok_or_else(|| format!("Empty alternation on line {linenum}"))?);
+        
+        for (_, words) in &terms
+        {
+            match words.iter().map(|w| w.as_str()).collect::<Vec<_>>()[..]
+            {
+                [] | ["->", "pop"] | ["->", "push", _] => {}
+                _ => return Err(format!("Broken mode action on line {linenum}; expected -> push <mode> or -> pop")),
+            }
+        }
+        modes.push((name.to_string(), skip_whitespace, terms));
+    }
+    Ok(modes)
+}
+
+#[allow(unused)]
+pub fn grammar_convert(input: &Vec<(String, Vec<Vec<String>>)>) -> Result<Grammar, String>
+{
+    Ok(grammar_convert_with_terminals(input, &[])?.0)
+}
+
+// grammar_convert(), but also gives ids to terminals that the rules might not use (like the ones that only @nofollow
+//  mentions), and returns the id of each of them.
+pub fn grammar_convert_with_terminals(input: &Vec<(String, Vec<Vec<String>>)>, terminals : &[String]) -> Result<(Grammar, Vec<TermId>), String>
+{
+    let mut by_name = HashMap::new();
+    for (index, (name, _)) in input.iter().enumerate()
+    {
+        if by_name.insert(name.clone(), index).is_some()
+        {
+            return Err(format!("Duplicate rule {name}; use alternations (e.g. x ::= a | b), not additional definitions (like x ::= a [...] x ::= b)"));
+        }
+    }
+    
+    // Literal ids are indexes into the sorted list of distinct literals, so that list has to exist before anything refers to it.
+    let mut literals = HashSet::new();
+    let mut case_insensitive = HashSet::new();
+    let mut case_sensitive = HashSet::new();
+    for term_str in input.iter().flat_map(|(_, raw_forms)| raw_forms.iter().flatten()).chain(terminals)
+    {
+        if is_bnf_literal(term_str)
+        {
+            let text = bnf_literal_text(term_str);
+            if is_bnf_ci_literal(term_str) { case_insensitive.insert(text.clone()); } else { case_sensitive.insert(text.clone()); }
+            literals.insert(text);
+        }
+        else if let Some(literal) = layout_literal(&by_name, term_str) { literals.insert(literal.to_string()); }
+    }
+    // A token can only be one literal, so "SELECT" and i"select" can't both exist.
+    if let Some(text) = case_sensitive.iter().find(|text| case_insensitive.contains(&text.to_lowercase()))
+    {
+        return Err(format!("The literal \"{text}\" is also used case-insensitively"));
+    }
+    let mut literals = literals.into_iter().collect::<Vec<_>>();
+    literals.sort();
+    let literal_ids = literals.iter().enumerate().map(|(i, text)| (text.as_str(), i)).collect::<HashMap<_, _>>();
+    let case_insensitive = literals.iter().enumerate().filter(|(_, text)| case_insensitive.contains(*text)).map(|(i, _)| i).collect();
+    
+    let mut points = Vec::new();
+    let mut regexes = Vec::new();
+    let mut byte_regexes = Vec::new();
+    let mut regex_exclusions = Vec::new();
+    // Keyed on (whether it's a byte regex, pattern, exclusions).
+    let mut regex_ids = HashMap::new();
+    let never_match = (Regex::new(NEVER_MATCH_PATTERN).unwrap(), Regex::new(&format!("{NEVER_MATCH_PATTERN}\\z")).unwrap());
+    // The extra terminals get converted like the alternations of one more rule, which doesn't become a GrammarPoint.
+    let extra = (String::new(), terminals.iter().map(|term_str| vec!(term_str.clone())).collect::<Vec<_>>());
+    let mut term_ids = Vec::new();
+    for (index, (name, raw_forms)) in input.iter().chain([&extra]).enumerate()
+    {
+        let mut forms = Vec::new();
+        
+        for raw_alt in raw_forms
+        {
+            let mut matching_terms = Vec::new();
+            
+            let mut terms = raw_alt.iter().peekable();
+            while let Some(term_str) = terms.next()
+            {
+                if is_bnf_literal(term_str)
+                {
+                    let literal = bnf_literal_text(term_str);
+                    if is_layout_literal(&literal)
+                    {
+                        return Err(format!("The literal {term_str} is reserved for layout tokens"));
+                    }
+                    matching_terms.push(MatchingTerm::TermLit(literal_ids[literal.as_str()]));
+                    continue;
+                }
+                if is_bnf_regex(term_str)
+                {
+                    let pattern = &term_str[3..term_str.len() - 3];
+                    let pattern_all = format!("\\A{pattern}\\z"); // full match (for parsing)
+                    let pattern = format!("\\A{pattern}"); // at start (for tokenization)
+                    let re = Regex::new(&pattern).map_err(|e| format!("Invalid regex '{}': {}", pattern, e))?;
+                    let re2 = Regex::new(&pattern_all).map_err(|e| format!("Invalid regex '{}': {}", pattern_all, e))?;
+                    // Exclusions: each - is followed by a literal, or a rule whose alternations are all single literals.
+                    let mut excluded = Vec::new();
+                    while terms.peek().is_some_and(|term_str| *term_str == "-")
+                    {
+                        terms.next();
+                        let excluded_str = terms.next().ok_or_else(|| format!("Missing exclusion after - in {name}"))?;
+                        let error = || format!("Can only exclude literals, or rules whose alternations are all single literals, not {excluded_str}");
+                        let excluded_literals = match by_name.get(excluded_str)
+                        {
+                            Some(id) => input[*id].1.iter().map(|alt| match &alt[..]
+                            {
+                                [literal] if is_bnf_literal(literal) => Ok(literal),
+                                _ => Err(error()),
+                            }).collect::<Result<Vec<_>, _>>()?,
+                            None if is_bnf_literal(excluded_str) => vec!(excluded_str),
+                            None => return Err(error()),
+                        };
+                        for literal in excluded_literals
+                        {
+                            excluded.push(literal_ids[bnf_literal_text(literal).as_str()]);
+                        }
+                    }
+                    excluded.sort();
+                    excluded.dedup();
+                    let next_id = regex_ids.len();
+                    let id = *regex_ids.entry((false, pattern_all, excluded.clone())).or_insert(next_id);
+                    if id == next_id
+                    {
+                        regex_exclusions.push(excluded);
+                        byte_regexes.push(regex::bytes::Regex::new(&pattern).map_err(|e| format!("Invalid regex '{}': {}", pattern, e))?);
+                        regexes.push(re.clone());
+                    }
+                    matching_terms.push(MatchingTerm::TermRegex(RegexCacher::new(id, re2)));
+                    continue;
+                }
+                if !by_name.contains_key(term_str) && let Some(pattern) = bnf_byte_pattern(term_str)
+                {
+                    let next_id = regex_ids.len();
+                    let id = *regex_ids.entry((true, pattern.clone(), Vec::new())).or_insert(next_id);
+                    if id == next_id
+                    {
+                        regex_exclusions.push(Vec::new());
+                        let pattern = format!("\\A(?:{pattern})");
+                        let re = regex::bytes::RegexBuilder::new(&pattern).unicode(false).build();
+                        byte_regexes.push(re.map_err(|e| format!("Invalid byte regex '{}': {}", pattern, e))?);
+                        regexes.push(never_match.0.clone());
+                    }
+                    matching_terms.push(MatchingTerm::TermRegex(RegexCacher::new(id, never_match.1.clone())));
+                    continue;
+                }
+                if let Some(literal) = layout_literal(&by_name, term_str)
+                {
+                    matching_terms.push(MatchingTerm::TermLit(literal_ids[literal]));
+                    continue;
+                }
+                let id = by_name.get(term_str).ok_or_else(|| format!("Not a defined grammar rule: '{}'", term_str))?;
+                matching_terms.push(MatchingTerm::Rule(*id));
+            }
+            if matching_terms.len() > 60000
+            {
+                return Err(format!("More than 60k items in an alternation of {name}. Factor them out, dummy!"));
+            }
+            forms.push(Alternation { matching_terms });
+        }
+        if forms.len() > 60000
+        {
+            return Err(format!("More than 60k alternations in {name}. Factor them out, dummy!"));
+        }
+        if index == input.len()
+        {
+            for alt in forms
+            {
+                term_ids.push(match &alt.matching_terms[..]
+                {
+                    [MatchingTerm::TermLit(id)] => TermId::Lit(*id),
+                    [MatchingTerm::TermRegex(regex)] => TermId::Regex(regex.id),
+                    _ => return Err(format!("Not a terminal: {:?}", alt.matching_terms)),
+                });
+            }
+            break;
+        }
+        points.push(GrammarPoint
+        {
+            name: Arc::new(name.clone()),
+            id: index,
+            forms,
+        });
+    }
+    if points.len() > 4000000000
+    {
+        return Err(format!("More than 4 billion grammar terms in grammar. What are you doing??? STOP!!!!! (╯°□°）╯︵ ┻━┻"));
+    }
+    
+    Ok((Grammar { points, by_name, literals, case_insensitive, regexes, byte_regexes, regex_exclusions, lexer_modes : Vec::new(), comments : Vec::new(), lengths : HashMap::new(), restrictions : HashMap::new(), converters : HashMap::new() }, term_ids))
+}
+
+// Bare INDENT, DEDENT and NEWLINE are layout terminals, unless the grammar defines rules with those names.
+fn layout_literal(by_name : &HashMap<String, usize>, term_str : &str) -> Option<&'static str>
+{
+    if by_name.contains_key(term_str) { return None; }
+    LAYOUT_TERMINALS.iter().find(|(name, _)| *name == term_str).map(|(_, literal)| *literal)
+}
+
+// Turns the output of bnf_parse_lexer_modes() into g.lexer_modes.
+// Terminals that aren't in any mode other than the default mode are in the default mode.
+pub fn grammar_set_lexer_modes(g : &mut Grammar, input : &[(String, bool, Vec<(String, Vec<String>)>)]) -> Result<(), String>
+{
+    if input.is_empty() { return Ok(()); }
+    
+    let mut by_name = HashMap::new();
+    by_name.insert("default", 0);
+    for (name, _, _) in input
+    {
+        if name != "default" && by_name.insert(name.as_str(), by_name.len()).is_some()
+        {
+            return Err(format!("Duplicate lexer mode {name}"));
+        }
+    }
+    let mut names = by_name.iter().map(|(name, index)| (*index, name.to_string())).collect::<Vec<_>>();
+    names.sort();
+    let mut modes = names.into_iter().map(|(_, name)| LexerMode {
+        name, skip_whitespace : true, terms : HashSet::new(), actions : HashMap::new()
+    }).collect::<Vec<_>>();
+    
+    // Modes are about lexing, so a regex that the grammar uses with different exclusions is in the mode as all of them.
+    let term_ids = |term_str : &str|
+    {
+        let ids = if is_bnf_literal(term_str) { g.terminal(term_str).into_iter().collect() } else { g.regex_terminals(term_str) };
+        if ids.is_empty() { return Err(format!("Lexer mode terminal {term_str} isn't used by any grammar rule")); }
+        Ok(ids)
+    };
+    let mut declared_default = false;
+    for (name, skip_whitespace, terms) in input
+    {
+        if name == "default"
+        {
+            if declared_default { return Err(format!("Duplicate lexer mode {name}")); }
+            declared_default = true;
+        }
+        let mode = &mut modes[by_name[name.as_str()]];
+        mode.skip_whitespace = *skip_whitespace;
+        for (term_str, words) in terms
+        {
+            let ids = term_ids(term_str)?;
+            mode.terms.extend(ids.iter().copied());
+            let action = match words.iter().map(|w| w.as_str()).collect::<Vec<_>>()[..]
+            {
+                ["->", "pop"] => ModeAction::Pop,
+                ["->", "push", target] => ModeAction::Push(*by_name.get(target).ok_or_else(|| format!("Not a defined lexer mode: '{target}'"))?),
+                _ => continue,
+            };
+            for id in ids { mode.actions.insert(id, action); }
+        }
+    }
+    
+    let claimed = modes[1..].iter().flat_map(|mode| mode.terms.iter().copied()).collect::<HashSet<_>>();
+    let all_terms = (0..g.literals.len()).map(TermId::Lit).chain((0..g.regexes.len()).map(TermId::Regex));
+    modes[0].terms.extend(all_terms.filter(|term| !claimed.contains(term)));
+    
+    g.lexer_modes = modes;
+    Ok(())
+}
+
+// Works out where each @length construct's length token is, relative to the construct, in every alternation that uses it.
+// Only terms that are always exactly one token long can go between the two, so that it's always the same number of tokens back.
+pub fn grammar_set_lengths(g : &mut Grammar, input : &[(String, String, bool)]) -> Result<(), String>
+{
+    // Terminals, and rules whose every alternation is a single terminal.
+    let single_token = |mt : &MatchingTerm| match mt
+    {
+        MatchingTerm::Rule(id) => g.points[*id].forms.iter().all(|alt| matches!(&alt.matching_terms[..], [t] if !matches!(t, MatchingTerm::Rule(_)))),
+        _ => true,
+    };
+    let mut lengths = HashMap::new();
+    for (name, len_term, bytes) in input
+    {
+        let id = *g.by_name.get(name).ok_or_else(|| format!("@length for undefined rule {name}"))?;
+        let is_len_term = |mt : &MatchingTerm| match (mt, g.by_name.get(len_term))
+        {
+            (MatchingTerm::Rule(id), Some(len_id)) => id == len_id,
+            (MatchingTerm::TermLit(id), None) => g.terminal(len_term) == Some(TermId::Lit(*id)),
+            (MatchingTerm::TermRegex(r), None) => g.regex_terminals(len_term).contains(&TermId::Regex(r.id)),
+            _ => false,
+        };
+        // (Not counting its own recursion, like payload ::= payload byte.)
+        for point in g.points.iter().filter(|point| point.id != id)
+        {
+            for (alt, form) in point.forms.iter().enumerate()
+            {
+                for (pos, mt) in form.matching_terms.iter().enumerate()
+                {
+                    if !matches!(mt, MatchingTerm::Rule(x) if *x == id) { continue; }
+                    let Some(len_pos) = form.matching_terms[..pos].iter().rposition(|mt| is_len_term(mt) || !single_token(mt)) else
+                    {
+                        return Err(format!("Rule {} uses {name} without a {len_term} before it", point.name));
+                    };
+                    if !is_len_term(&form.matching_terms[len_pos])
+                    {
+                        return Err(format!("Rule {} has something other than single tokens between {len_term} and {name}", point.name));
+                    }
+                    if !single_token(&form.matching_terms[len_pos])
+                    {
+                        return Err(format!("Length term {len_term} isn't always a single token"));
+                    }
+                    lengths.insert((point.id, alt, pos), LengthCheck { back : pos - len_pos, bytes : *bytes });
+                }
+            }
+        }
+    }
+    g.lengths = lengths;
+    Ok(())
+}
+
+pub fn bnf_to_grammar(s : &str) -> Result<Grammar, String>
+{
+    let (rules, restrictions) = bnf_apply_directives(bnf_parse(s)?, &bnf_parse_scannerless(s)?)?;
+    let (mut g, restriction_term_ids) = grammar_convert_with_terminals(&rules, &bnf_restriction_terminals(&restrictions))?;
+    grammar_set_lexer_modes(&mut g, &bnf_parse_lexer_modes(s)?)?;
+    grammar_set_lengths(&mut g, &bnf_parse_lengths(s)?)?;
+    grammar_set_restrictions(&mut g, &restrictions, &restriction_term_ids)?;
+    for (name, converter) in bnf_parse_converters(s)?
+    {
+        g.set_converter(&name, converter)?;
+    }
+    for pattern in bnf_parse_comments(s)?
+    {
+        let pattern = format!("\\A(?:{pattern})");
+        g.comments.push(Regex::new(&pattern).map_err(|e| format!("Invalid regex '{}': {}", pattern, e))?);
+    }
+    Ok(g)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Token {
+    pub text : SymbolId,
+    // Which terminals matched this token: the id of the literal with the same text (if any),
+    //  and the ids of every regex that matches all of it.
+    pub lit : Option<usize>,
+    pub regexes : Arc<[usize]>,
+    // Covers input that no terminal matched (see tokenize_recovering()). Doesn't match any terminal either.
+    #[allow(unused)]
+    pub error : bool,
+    pub span : TokenSpan,
+    // The integer this token stands for as each of its terminals, for @length (see LengthCheck): its text as a decimal
+    //  number, or as a byte terminal from tokenize_bytes(), its bytes as a big-endian number. None if there aren't any.
+    pub values : Option<Arc<[(TermId, u64)]>>,
+    // Where the capture groups of each of its regexes that has any matched (see TokenCaptures). None if there aren't any.
+    pub captures : Option<Arc<[Arc<TokenCaptures>]>>,
+}
+
+// Turns the text of a terminal's tokens into a typed value for their AST leaves (see ASTNode::converted), so that consumers
+//  don't each have to parse it again. From @convert directives (int, float, string or bool), or Grammar::set_converter().
+#[derive(Clone)]
+pub enum Converter {
+    Int,
+    Float,
+    // Strips the quotes (if it starts and ends with the same one), and resolves the same escapes as grammar literals.
+    String,
+    // true or false.
+    Bool,
+    #[allow(unused)]
+    Custom(Arc<dyn Fn(&str) -> Result<LeafValue, String> + Send + Sync>),
+}
+
+impl std::fmt::Debug for Converter {
+    fn fmt(&self, f : &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self {
+            Converter::Int => write!(f, "Int"),
+            Converter::Float => write!(f, "Float"),
+            Converter::String => write!(f, "String"),
+            Converter::Bool => write!(f, "Bool"),
+            Converter::Custom(_) => write!(f, "Custom(..)"),
+        }
+    }
+}
+
+impl Converter {
+    pub fn convert(&self, text : &str) -> Result<LeafValue, String>
+    {
+        match self {
+            Converter::Int => text.parse().map(LeafValue::Int).map_err(|e| format!("Bad integer '{text}': {e}")),
+            Converter::Float => text.parse().map(LeafValue::Float).map_err(|e| format!("Bad float '{text}': {e}")),
+            Converter::String =>
+            {
+                let inner = match text.as_bytes()
+                {
+                    [quote @ (b'"' | b'\''), .., last] if last == quote => &text[1..text.len() - 1],
+                    _ => text,
+                };
+                bnf_unescape(inner).map(LeafValue::Str)
+            }
+            Converter::Bool => match text
+            {
+                "true" => Ok(LeafValue::Bool(true)),
+                "false" => Ok(LeafValue::Bool(false)),
+                _ => Err(format!("Bad boolean '{text}'")),
+            },
+            Converter::Custom(convert) => convert(text),
+        }
+    }
+}
+
+// Converts the text of AST leaves as the parsers build them, by whichever terminal each leaf matched, and only once
+//  per distinct text and terminal. Use one per AST. symbols is the Interner that the tokens were tokenized with.
+#[derive(Default)]
+pub struct LeafConverter {
+    cache : rustc_hash::FxHashMap<(SymbolId, TermId), Result<Arc<LeafValue>, String>>,
+}
+
+impl LeafConverter {
+    // None if term doesn't have a converter.
+    pub fn convert(&mut self, g : &Grammar, symbols : &Interner, token : &Token, term : TermId) -> Result<Option<Arc<LeafValue>>, String>
+    {
+        let Some(converter) = g.converters.get(&term) else { return Ok(None) };
+        let converted = self.cache.entry((token.text, term)).or_insert_with(|| converter.convert(symbols.text(token.text)).map(Arc::new));
+        converted.clone().map(Some)
+    }
+}
+
+// A converted leaf (see Converter). Custom converters can return anything, and the consumer downcasts it.
+#[derive(Debug, Clone)]
+pub enum LeafValue {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Bool(bool),
+    #[allow(unused)]
+    Custom(Arc<dyn std::any::Any + Send + Sync>),
+}
+
+// Custom values are only equal if they're the same value.
+impl PartialEq for LeafValue {
+    fn eq(&self, other : &LeafValue) -> bool
+    {
+        match (self, other) {
+            (LeafValue::Int(a), LeafValue::Int(b)) => a == b,
+            (LeafValue::Float(a), LeafValue::Float(b)) => a == b,
+            (LeafValue::Str(a), LeafValue::Str(b)) => a == b,
+            (LeafValue::Bool(a), LeafValue::Bool(b)) => a == b,
+            (LeafValue::Custom(a), LeafValue::Custom(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+// Where a regex terminal's capture groups matched in a token, so that the AST leaf for it doesn't have to run the regex
+//  again (see ASTNode::capture). Each group is a byte range in the token's text: group 0 is the whole token,
+//  and groups that didn't take part in the match are None. Group names are on the regex (see CompiledGrammar::regexes).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenCaptures {
+    pub regex : usize,
+    pub groups : Box<[Option<(usize, usize)>]>,
+}
+
+// Where a token is in the input, in bytes, including the trivia (whitespace and comments) around it:
+//  leading..start is its leading trivia, start..end is the token itself, and end..trailing is its trailing trivia.
+// Trailing trivia runs up to the end of the line that the token is on, and the rest of the trivia before the next token
+//  is that token's leading trivia, so every byte of the input belongs to exactly one token.
+// Tokens that aren't in the input (like INDENT) are empty, and are at the start of the next token's leading trivia.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TokenSpan {
+    pub leading : usize,
+    pub start : usize,
+    pub end : usize,
+    pub trailing : usize,
+}
+
+impl TokenSpan {
+    // Without any trivia. assign_trivia() fills it in.
+    pub fn new(start : usize, end : usize) -> TokenSpan
+    {
+        TokenSpan { leading : start, start, end, trailing : end }
+    }
+}
+
+// Gives each token its share of the trivia between it and its neighbors (see TokenSpan). The tokens have to be in order,
+//  and their start and end have to be set already.
+pub fn assign_trivia(s : &str, tokens : &mut [Token])
+{
+    let mut prev : Option<usize> = None;
+    let mut empty = Vec::new();
+    for i in 0..tokens.len()
+    {
+        let span = tokens[i].span;
+        if span.start == span.end
+        {
+            empty.push(i);
+            continue;
+        }
+        let split = match prev
+        {
+            Some(prev) =>
+            {
+                let gap = tokens[prev].span.end..span.start;
+                let split = s[gap.clone()].find('\n').map_or(gap.end, |newline| gap.start + newline + 1);
+                tokens[prev].span.trailing = split;
+                split
+            }
+            None => 0,
+        };
+        tokens[i].span.leading = split;
+        for j in empty.drain(..) { tokens[j].span = TokenSpan::new(split, split); }
+        prev = Some(i);
+    }
+    if let Some(prev) = prev { tokens[prev].span.trailing = s.len(); }
+    for j in empty { tokens[j].span = TokenSpan::new(s.len(), s.len()); }
+}
+
+impl Token {
+    // For tokens from other lexers (see Tokenizer): terms is every terminal that the token counts as,
+    //  which is all that the parsers look at when scanning it. The text is only for the AST, so it can be anything.
+    pub fn new(symbols : &mut Interner, text : &str, terms : &[TermId]) -> Token
+    {
+        let mut lit = None;
+        let mut regexes = Vec::new();
+        for term in terms
+        {
+            match term {
+                TermId::Lit(id) => lit = Some(*id),
+                TermId::Regex(id) => regexes.push(*id),
+            }
+        }
+        regexes.sort();
+        regexes.dedup();
+        let mut token = Token { text : symbols.intern(text), lit, regexes : regexes.into(), error : false, span : <_>::default(), values : None, captures : None };
+        token.values = token_values(text, token.lit, &token.regexes);
+        token
+    }
+    // The integer this token stands for as this terminal (see Token::values).
+    pub fn value(&self, term : TermId) -> Option<u64>
+    {
+        self.values.as_ref()?.iter().find(|(t, _)| *t == term).map(|(_, value)| *value)
+    }
+    // The token's bytes, for tokens from tokenize_bytes(). input is what got tokenized.
+    #[allow(unused)]
+    pub fn bytes<'a>(&self, input : &'a [u8]) -> &'a [u8]
+    {
+        &input[self.span.start..self.span.end]
+    }
+    pub fn error(symbols : &mut Interner, text : &str, span : TokenSpan) -> Token
+    {
+        Token { text : symbols.intern(text), lit : None, regexes : <_>::default(), error : true, span, values : None, captures : None }
+    }
+    // The captures of one of the token's regexes, for the AST leaf that it becomes.
+    pub fn captures_for(&self, regex : usize) -> Option<Arc<TokenCaptures>>
+    {
+        self.captures.as_ref()?.iter().find(|c| c.regex == regex).cloned()
+    }
+    // Every terminal that matched this token, e.g. for syntax highlighting.
+    #[allow(unused)]
+    pub fn terms(&self) -> impl Iterator<Item = TermId> + '_
+    {
+        self.lit.map(TermId::Lit).into_iter().chain(self.regexes.iter().map(|&id| TermId::Regex(id)))
+    }
+}
+
+// Which regex terminals each token matches, as one bitset per distinct set of regex ids.
+// Built once per input from the regex ids that tokenize() attached to each token, so that scan checks
+//  during parsing are a bit test instead of a regex match or a trip through RegexCacher's locked cache.
+// (Not per distinct text: in a lattice, the same text can match different regexes in different places.)
+#[derive(Debug, Clone, Default)]
+pub struct RegexMatchTable {
+    // u64s per bitset (enough for one bit per regex id).
+    words : usize,
+    bits : Vec<u64>,
+    // Which bitset each token uses.
+    rows : Vec<u32>,
+    row_of : rustc_hash::FxHashMap<Arc<[usize]>, u32>,
+}
+
+impl RegexMatchTable {
+    pub fn new(cg : &CompiledGrammar, tokens : &[Token]) -> RegexMatchTable
+    {
+        let mut table = RegexMatchTable { words : cg.regexes.len().div_ceil(64), ..<_>::default() };
+        for token in tokens
+        {
+            table.push(token);
+        }
+        table
+    }
+    // Adds the next token.
+    pub fn push(&mut self, token : &Token)
+    {
+        let row = match self.row_of.get(&token.regexes)
+        {
+            Some(row) => *row,
+            None =>
+            {
+                let row = self.row_of.len();
+                self.bits.resize(self.bits.len() + self.words, 0);
+                for &id in token.regexes.iter()
+                {
+                    self.bits[row * self.words + id / 64] |= 1 << (id % 64);
+                }
+                self.row_of.insert(Arc::clone(&token.regexes), row as u32);
+                row as u32
+            }
+        };
+        self.rows.push(row);
+    }
+    // Does the token at this index match the regex terminal with this id?
+    pub fn matches(&self, token_index : usize, regex_id : usize) -> bool
+    {
+        let row = self.rows[token_index] as usize;
+        self.bits[row * self.words + regex_id / 64] & (1 << (regex_id % 64)) != 0
+    }
+}
+
+// Sort literals from grammar by length and combine them into a single match-longest regex.
+pub fn build_literal_regex(g : &Grammar) -> Regex
+{
+    let mut text_token_regex_s = "^(".to_string();
+    
+    let mut lits = (0..g.literals.len()).filter(|&id| !is_layout_literal(&g.literals[id])).collect::<Vec<_>>();
+    lits.sort_by(|a, b| g.literals[*b].len().cmp(&g.literals[*a].len()));
+    for id in lits
+    {
+        let s2 = g.literal_pattern(id);
+        text_token_regex_s += &s2;
+        text_token_regex_s += "|";
+    }
+    if text_token_regex_s.ends_with("|") { text_token_regex_s.pop(); }
+    text_token_regex_s += ")";
+    let text_token_regex = Regex::new(&text_token_regex_s).unwrap();
+    text_token_regex
+}
+
+// Every terminal in the grammar (literals first, then regexes, in id order) compiled into a single lazy DFA,
+//  so that tokenize() can find the longest match at a position in one pass instead of trying each terminal.
+// The DFA's state cache is per search, not per grammar, so this stays shareable between threads.
+#[derive(Debug)]
+pub struct TokenMatcher {
+    dfa : regex_automata::hybrid::dfa::DFA,
+    literal_count : usize,
+    // Grammar::regexes, for checking how much each regex terminal really matches (see walk()).
+    regexes : Vec<Regex>,
+}
+
+impl TokenMatcher {
+    pub fn new(g : &Grammar, regexes : &[RegexCacher]) -> Result<TokenMatcher, String>
+    {
+        use regex_automata::{hybrid::dfa::DFA, MatchKind};
+        // Layout literals still get a pattern (one that can't match anything), so that pattern ids stay the same as literal ids.
+        let mut patterns = (0..g.literals.len()).map(|id| match &g.literals[id]
+        {
+            text if is_layout_literal(text) => "[^\\s\\S]".to_string(),
+            _ => g.literal_pattern(id),
+        }).collect::<Vec<_>>();
+        for regex in regexes
+        {
+            // The parsing version of each regex is wrapped in \A...\z; we want the bare pattern.
+            let pattern = regex.regex().as_str();
+            patterns.push(format!("(?:{})", &pattern[2..pattern.len() - 2]));
+        }
+        // MatchKind::All keeps going past the first match, so we see every terminal that matches and the longest one.
+        // Non-ASCII text next to \b makes the lazy DFA give up, which tokenize() handles by falling back to the regexes.
+        let dfa = DFA::builder()
+            .configure(DFA::config().match_kind(MatchKind::All).unicode_word_boundary(true))
+            .build_many(&patterns)
+            .map_err(|e| format!("Failed to build tokenizer DFA: {e}"))?;
+        Ok(TokenMatcher { dfa, literal_count : g.literals.len(), regexes : g.regexes.clone() })
+    }
+    pub fn create_cache(&self) -> regex_automata::hybrid::dfa::Cache
+    {
+        self.dfa.create_cache()
+    }
+    // Runs the DFA over s until it can't match anything longer, calling found(len, terms) for every non-empty match,
+    //  shortest first, where terms is every terminal that matches exactly that much of s. Stops early if found() returns true.
+    // The DFA sees every length that a regex could match, but a regex terminal never matches more than its own leftmost-first
+    //  search does (so rx%/\*.*?\*/%rx stops at the first */), so longer matches of a regex don't get reported.
+    fn walk(&self, cache : &mut regex_automata::hybrid::dfa::Cache, s : &str, mut found : impl FnMut(usize, Vec<TermId>) -> bool) -> Result<(), regex_automata::MatchError>
+    {
+        use regex_automata::{Anchored, Input, MatchError};
+        let dfa = &self.dfa;
+        // (regex id, length of its own match), for the regexes that the DFA has reported so far.
+        let mut own_lens = Vec::<(usize, usize)>::new();
+        let mut terms = |cache : &regex_automata::hybrid::dfa::Cache, sid, len| (0..dfa.match_len(cache, sid)).filter_map(|i|
+        {
+            let id = dfa.match_pattern(cache, sid, i).as_usize();
+            if id < self.literal_count { return Some(TermId::Lit(id)); }
+            let id = id - self.literal_count;
+            let own_len = match own_lens.iter().find(|x| x.0 == id)
+            {
+                Some(x) => x.1,
+                None =>
+                {
+                    let own_len = self.regexes[id].find(s).map(|m| m.len()).unwrap_or(0);
+                    own_lens.push((id, own_len));
+                    own_len
+                }
+            };
+            (len <= own_len).then_some(TermId::Regex(id))
+        }).collect::<Vec<_>>();
+        let mut sid = dfa.start_state_forward(cache, &Input::new(s).anchored(Anchored::Yes))?;
+        // Matches are reported one byte late: being in a match state after reading byte i means there's a match that ends at i.
+        for (i, b) in s.bytes().enumerate()
+        {
+            sid = dfa.next_state(cache, sid, b).map_err(|_| MatchError::gave_up(i))?;
+            if sid.is_tagged()
+            {
+                if sid.is_match() && i > 0
+                {
+                    let terms = terms(cache, sid, i);
+                    if !terms.is_empty() && found(i, terms) { return Ok(()); }
+                }
+                else if sid.is_dead() { return Ok(()); }
+                else if sid.is_quit() { return Err(MatchError::quit(b, i)); }
+            }
+        }
+        sid = dfa.next_eoi_state(cache, sid).map_err(|_| MatchError::gave_up(s.len()))?;
+        if sid.is_match() && !s.is_empty()
+        {
+            let terms = terms(cache, sid, s.len());
+            if !terms.is_empty() { found(s.len(), terms); }
+        }
+        Ok(())
+    }
+    // Length of the longest non-empty match at the start of s, and every terminal that matches exactly that much of it.
+    pub fn longest_match(&self, cache : &mut regex_automata::hybrid::dfa::Cache, s : &str) -> Result<Option<(usize, Vec<TermId>)>, regex_automata::MatchError>
+    {
+        let mut last = None;
+        self.walk(cache, s, |len, terms| { last = Some((len, terms)); false })?;
+        Ok(last)
+    }
+    // Every non-empty match at the start of s, shortest first.
+    pub fn all_matches(&self, cache : &mut regex_automata::hybrid::dfa::Cache, s : &str) -> Result<Vec<(usize, Vec<TermId>)>, regex_automata::MatchError>
+    {
+        let mut all = Vec::new();
+        self.walk(cache, s, |len, terms| { all.push((len, terms)); false })?;
+        Ok(all)
+    }
+    // Whether any allowed terminal matches a non-empty prefix of s. Stops at the first match, instead of looking for longer ones.
+    pub fn matches_any(&self, cache : &mut regex_automata::hybrid::dfa::Cache, s : &str, allowed : impl Fn(&TermId) -> bool) -> Result<bool, regex_automata::MatchError>
+    {
+        let mut any = false;
+        self.walk(cache, s, |_, terms| { any = terms.iter().any(&allowed); any })?;
+        Ok(any)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TokenizeOptions {
+    // Emit INDENT, DEDENT and NEWLINE tokens (see LAYOUT_TERMINALS) based on line-leading whitespace, like Python does.
+    // Lines that are only whitespace don't count. Newlines inside of brackets aren't treated specially.
+    pub indentation : bool,
+}
+
+// A lexical error, i.e. something that tokenize() would have given up on. start..end is the byte range it's about.
+#[derive(Debug, Clone)]
+#[allow(unused)]
+pub struct TokenizeError {
+    pub start : usize,
+    pub end : usize,
+    pub message : String,
+}
+
+// Interns the text of the tokens in symbols. The grammar is only read, so many threads can tokenize with it at once.
+pub fn tokenize(cg : &CompiledGrammar, symbols : &mut Interner, s : &str) -> Result<Vec<Token>, String>
+{
+    tokenize_with_options(cg, symbols, s, &TokenizeOptions::default())
+}
+
+pub fn tokenize_with_options(cg : &CompiledGrammar, symbols : &mut Interner, s : &str, options : &TokenizeOptions) -> Result<Vec<Token>, String>
+{
+    tokenize_impl(cg, symbols, s, options, None)
+}
+
+// Anything that turns input into tokens for the parsers. Implement this to use your own lexer instead of tokenize():
+//  look up the TermIds of your kinds of token once with Grammar::terminal(), and build tokens with Token::new().
+#[allow(unused)]
+pub trait Tokenizer {
+    fn tokenize(&mut self, cg : &CompiledGrammar, symbols : &mut Interner, input : &str) -> Result<Vec<Token>, String>;
+}
+
+// The built-in regex/literal tokenizer, i.e. tokenize_with_options().
+#[derive(Debug, Clone, Default)]
+#[allow(unused)]
+pub struct DefaultTokenizer {
+    pub options : TokenizeOptions,
+}
+
+impl Tokenizer for DefaultTokenizer {
+    fn tokenize(&mut self, cg : &CompiledGrammar, symbols : &mut Interner, input : &str) -> Result<Vec<Token>, String>
+    {
+        tokenize_with_options(cg, symbols, input, &self.options)
+    }
+}
+
+// Like tokenize_with_options(), but doesn't stop at lexical errors. Input that nothing matches becomes an error token
+//  (see Token::error) that runs up to the next whitespace or the next place where something does match, and every
+//  lexical error gets returned alongside the tokens. Error tokens don't match any terminal, so parsing the tokens
+//  reports a syntax error at the first one (or earlier), and both kinds of error can be shown at once.
+#[allow(unused)]
+pub fn tokenize_recovering(cg : &CompiledGrammar, symbols : &mut Interner, s : &str, options : &TokenizeOptions) -> (Vec<Token>, Vec<TokenizeError>)
+{
+    let mut diagnostics = Vec::new();
+    let tokens = tokenize_impl(cg, symbols, s, options, Some(&mut diagnostics)).expect("Lexical errors are recorded instead of returned");
+    (tokens, diagnostics)
+}
+
+// With diagnostics, the error gets recorded and tokenizing goes on. Without, it's returned, which ends tokenizing.
+fn report(diagnostics : &mut Option<&mut Vec<TokenizeError>>, error : TokenizeError) -> Result<(), String>
+{
+    match diagnostics
+    {
+        Some(diagnostics) => { diagnostics.push(error); Ok(()) }
+        None => Err(error.message),
+    }
+}
+
+// Where the run of unlexable input starting at byte i of s ends: at whitespace (if it's being skipped),
+//  wherever something matches again, or at the end of the input.
+fn unrecognized_run_end(s : &str, mut i : usize, stop_at_whitespace : bool, mut matches_at : impl FnMut(usize) -> bool) -> usize
+{
+    i += get_char_at_byte(s, i).len_utf8();
+    while i < s.len() && !(stop_at_whitespace && get_char_at_byte(s, i).is_whitespace()) && !matches_at(i)
+    {
+        i += get_char_at_byte(s, i).len_utf8();
+    }
+    i
+}
+
+// Keeps track of indentation for TokenizeOptions::indentation.
+struct Layout<'a> {
+    // The indentation of each enclosing block, outermost first. The outermost one is "", unless the first line is indented.
+    indents : Vec<&'a str>,
+    // INDENT, DEDENT and NEWLINE tokens.
+    tokens : [Token; 3],
+}
+
+impl<'a> Layout<'a> {
+    fn new(cg : &CompiledGrammar, symbols : &mut Interner) -> Layout<'a>
+    {
+        let tokens = LAYOUT_TERMINALS.map(|(_, literal)|
+        {
+            Token { text : symbols.intern(literal), lit : cg.g.literal_id(literal), regexes : <_>::default(), error : false, span : <_>::default(), values : None, captures : None }
+        });
+        Layout { indents : vec!(""), tokens }
+    }
+    // Called with the whitespace that got skipped before the token at byte offset i of s.
+    fn skipped(&mut self, s : &'a str, whitespace : &'a str, i : usize, tokens : &mut Vec<Token>) -> Result<(), TokenizeError>
+    {
+        let line_start = match whitespace.rfind('\n')
+        {
+            Some(newline) => newline + 1,
+            None if i == whitespace.len() => 0,
+            None => return Ok(()),
+        };
+        let line = &whitespace[line_start..];
+        let indent = &line[..line.len() - line.trim_start().len()];
+        let start = i - (whitespace.len() - line_start);
+        let error = |what : &str, why : &str|
+        {
+            let line = s[..i].matches('\n').count() + 1;
+            TokenizeError { start, end : start + indent.len(), message : format!("{what} on line {line}: {why}") }
+        };
+        if tokens.is_empty() && !indent.is_empty()
+        {
+            // Like in Python, there's no block for it to be in. (If tokenizing goes on, it's as if the whole file was indented.)
+            self.indents[0] = indent;
+            return Err(error("Unexpected indentation", "the first line can't be indented"));
+        }
+        if !tokens.is_empty() { tokens.push(self.tokens[2].clone()); }
+        if indent.len() > self.indents.last().unwrap().len() && indent.starts_with(self.indents.last().unwrap())
+        {
+            self.indents.push(indent);
+            tokens.push(self.tokens[0].clone());
+            return Ok(());
+        }
+        while self.indents.len() > 1 && indent.len() < self.indents.last().unwrap().len()
+        {
+            self.indents.pop();
+            tokens.push(self.tokens[1].clone());
+        }
+        if indent != *self.indents.last().unwrap()
+        {
+            return Err(error("Inconsistent indentation", "it doesn't match any enclosing block's indentation"));
+        }
+        Ok(())
+    }
+    fn finish(&mut self, tokens : &mut Vec<Token>)
+    {
+        if !tokens.is_empty() { tokens.push(self.tokens[2].clone()); }
+        for _ in 1..self.indents.len() { tokens.push(self.tokens[1].clone()); }
+    }
+}
+
+fn tokenize_impl(cg : &CompiledGrammar, symbols : &mut Interner, mut s : &str, options : &TokenizeOptions, mut diagnostics : Option<&mut Vec<TokenizeError>>) -> Result<Vec<Token>, String>
+{
+    if !cg.g.lexer_modes.is_empty() { return tokenize_with_modes(cg, symbols, s, options, diagnostics); }
+    
+    let s_orig = s;
+    let mut layout = options.indentation.then(|| Layout::new(cg, symbols));
+    let mut tokens = vec!();
+    
+    let all_literals_regex = &cg.literal_regex;
+    let g = &cg.g;
+    
+    let mut cache = cg.token_matcher.as_ref().map(|m| m.create_cache());
+    // Tokens with the same text match the same terminals, so we only need to work out (and allocate) their terminals once.
+    let mut terms_of = rustc_hash::FxHashMap::<SymbolId, TokenTerms>::default();
+    
+    while !s.is_empty()
+    {
+        let skipped = skip_trivia(g, s, 0);
+        if skipped == s.len() { break; }
+        if let Some(layout) = &mut layout && let Err(e) = layout.skipped(s_orig, &s[..skipped], s_orig.len() - s.len() + skipped, &mut tokens)
+        {
+            report(&mut diagnostics, e)?;
+        }
+        s = &s[skipped..];
+        
+        let mut longest = 0;
+        let mut terms = None;
+        let found = match (&cg.token_matcher, &mut cache)
+        {
+            (Some(matcher), Some(cache)) => matcher.longest_match(cache, s).ok(),
+            _ => None,
+        };
+        match found
+        {
+            Some(found) => if let Some(found) = found
+            {
+                longest = found.0;
+                terms = Some(found.1);
+            },
+            // No DFA (or it gave up on this input): try each terminal one at a time.
+            None =>
+            {
+                for r in &g.regexes
+                {
+                    if let Some(loc) = r.find(s).map(|x| x.len())
+                    {
+                        longest = longest.max(loc);
+                    }
+                }
+                if let Some(loc) = all_literals_regex.find(s).map(|x| x.len())
+                {
+                    longest = longest.max(loc);
+                }
+            }
+        }
+        if longest == 0
+        {
+            let i = s_orig.len() - s.len();
+            let end = unrecognized_run_end(s_orig, i, true, |j| terminal_matches_any(cg, &mut cache, &s_orig[j..], |_| true));
+            report(&mut diagnostics, TokenizeError { start : i, end, message : format!("Failed to tokenize at index {i}") })?;
+            tokens.push(Token::error(symbols, &s_orig[i..end], TokenSpan::new(i, end)));
+            s = &s_orig[end..];
+            continue;
+        }
+        
+        let text = symbols.intern(&s[..longest]);
+        let (lit, regexes, values, captures) = terms_of.entry(text).or_insert_with(||
+        {
+            let lit = g.literal_id(&s[..longest]);
+            let regexes = match &terms
+            {
+                Some(terms) => terms.iter().filter_map(|t| if let TermId::Regex(id) = t { Some(*id) } else { None }).collect::<Vec<_>>(),
+                None => cg.regexes.iter().filter(|r| r.regex().is_match(&s[..longest])).map(|r| r.id).collect(),
+            };
+            let regexes : Arc<[usize]> = regexes.into_iter().filter(|&id| !g.excludes(id, lit)).collect();
+            let captures = token_captures(cg, &s[..longest], &regexes);
+            let values = token_values(&s[..longest], lit, &regexes);
+            (lit, regexes, values, captures)
+        });
+        let start = s_orig.len() - s.len();
+        tokens.push(Token { text, lit : *lit, regexes : Arc::clone(regexes), error : false, span : TokenSpan::new(start, start + longest), values : values.clone(),
+            captures : captures.clone() });
+        s = &s[longest..];
+    }
+    if let Some(layout) = &mut layout { layout.finish(&mut tokens); }
+    assign_trivia(s_orig, &mut tokens);
+    Ok(tokens)
+}
+
+// tokenize() for grammars with lexer modes (see LexerMode). The longest match overall might be for a terminal that isn't
+//  in the current mode, so this needs every match, not just the longest one.
+fn tokenize_with_modes(cg : &CompiledGrammar, symbols : &mut Interner, s : &str, options : &TokenizeOptions, mut diagnostics : Option<&mut Vec<TokenizeError>>) -> Result<Vec<Token>, String>
+{
+    let mut layout = options.indentation.then(|| Layout::new(cg, symbols));
+    let modes = &cg.g.lexer_modes;
+    let mut cache = cg.token_matcher.as_ref().map(|m| m.create_cache());
+    let mut regex_ids_of = rustc_hash::FxHashMap::<Vec<usize>, Arc<[usize]>>::default();
+    let mut tokens = vec!();
+    let mut stack = vec!(0);
+    let mut i = 0;
+    loop
+    {
+        let mode = &modes[*stack.last().unwrap()];
+        if mode.skip_whitespace
+        {
+            let skipped = skip_trivia(&cg.g, s, i);
+            if skipped >= s.len() { break; }
+            if let Some(layout) = &mut layout && let Err(e) = layout.skipped(s, &s[i..skipped], skipped, &mut tokens)
+            {
+                report(&mut diagnostics, e)?;
+            }
+            i = skipped;
+        }
+        if i >= s.len() { break; }
+        
+        let rest = &s[i..];
+        let found = terminal_matches(cg, &mut cache, rest);
+        let Some((len, terms)) = longest_allowed_match(&found, |t| mode.terms.contains(t)) else
+        {
+            let end = unrecognized_run_end(s, i, mode.skip_whitespace, |j|
+                terminal_matches_any(cg, &mut cache, &s[j..], |t| mode.terms.contains(t)));
+            let message = format!("Failed to tokenize at index {i} (in lexer mode {})", mode.name);
+            report(&mut diagnostics, TokenizeError { start : i, end, message })?;
+            tokens.push(Token::error(symbols, &s[i..end], TokenSpan::new(i, end)));
+            i = end;
+            continue;
+        };
+        // If a token is both a literal and a regex with different actions, the literal wins.
+        let lit = terms.iter().find(|t| matches!(t, TermId::Lit(_)));
+        match lit.into_iter().chain(terms.iter()).find_map(|t| mode.actions.get(t))
+        {
+            Some(ModeAction::Push(next)) => stack.push(*next),
+            Some(ModeAction::Pop) =>
+            {
+                if stack.len() > 1 { stack.pop(); }
+                else
+                {
+                    let message = format!("Lexer mode popped with nothing to return to at index {i}");
+                    report(&mut diagnostics, TokenizeError { start : i, end : i + len, message })?;
+                }
+            }
+            None => {}
+        }
+        tokens.push(token_from_terms(cg, symbols, &mut regex_ids_of, &rest[..len], TokenSpan::new(i, i + len), &terms));
+        i += len;
+    }
+    if stack.len() > 1
+    {
+        let message = format!("Input ended in lexer mode {}", modes[*stack.last().unwrap()].name);
+        report(&mut diagnostics, TokenizeError { start : s.len(), end : s.len(), message })?;
+    }
+    if let Some(layout) = &mut layout { layout.finish(&mut tokens); }
+    assign_trivia(s, &mut tokens);
+    Ok(tokens)
+}
+
+// A DAG of candidate tokens, for when the tokenizer can't decide on its own where tokens end (e.g. whether `>>` is
+//  one token or two). Nodes are positions in the input between tokens, and are what the parser uses as chart columns.
+// Every token is an edge from the node it starts at to the node it ends at.
+// A flat token list is the lattice where token i goes from node i to node i + 1. That one borrows the tokens instead of
+//  copying them (see from_tokens()), so parsing a flat token list doesn't pay for them twice.
+#[derive(Debug, Clone, Default)]
+pub struct TokenLattice<'a> {
+    // Edges, sorted by the node they start at. Edge ids index into these.
+    pub tokens : Cow<'a, [Token]>,
+    pub from : Vec<usize>,
+    pub to : Vec<usize>,
+    // Byte offset of each node in the input. Empty if the lattice was built from a flat token list.
+    #[allow(unused)]
+    pub offsets : Vec<usize>,
+    // Edges leaving node i are out_start[i]..out_start[i + 1].
+    out_start : Vec<usize>,
+    // Edges entering node i are in_edges[in_start[i]..in_start[i + 1]].
+    in_start : Vec<usize>,
+    in_edges : Vec<usize>,
+}
+
+impl<'a> TokenLattice<'a> {
+    // Edges are (from, to, token). Every edge has to go forwards (from < to), and node_count - 1 is the end of the input.
+    pub fn new(node_count : usize, mut edges : Vec<(usize, usize, Token)>, offsets : Vec<usize>) -> TokenLattice<'a>
+    {
+        edges.sort_by_key(|e| e.0);
+        let mut out_start = vec!(0; node_count + 1);
+        let mut in_start = vec!(0; node_count + 1);
+        for (from, to, _) in &edges
+        {
+            assert!(from < to && *to < node_count);
+            out_start[from + 1] += 1;
+            in_start[to + 1] += 1;
+        }
+        for i in 0..node_count
+        {
+            out_start[i + 1] += out_start[i];
+            in_start[i + 1] += in_start[i];
+        }
+        let mut in_edges = vec!(0; edges.len());
+        let mut in_next = in_start.clone();
+        for (id, (_, to, _)) in edges.iter().enumerate()
+        {
+            in_edges[in_next[*to]] = id;
+            in_next[*to] += 1;
+        }
+        let mut lattice = TokenLattice { out_start, in_start, in_edges, offsets, ..<_>::default() };
+        let mut tokens = Vec::with_capacity(edges.len());
+        for (from, to, token) in edges
+        {
+            lattice.from.push(from);
+            lattice.to.push(to);
+            tokens.push(token);
+        }
+        lattice.tokens = Cow::Owned(tokens);
+        lattice
+    }
+    // Adds a token from the last node to a new node at the given byte offset.
+    // Only works while the last node doesn't have any tokens starting at it yet.
+    pub fn push_token(&mut self, token : Token, offset : usize)
+    {
+        let node = self.node_count() - 1;
+        let id = self.tokens.len();
+        assert!(self.out_start[node] == id);
+        self.out_start[node + 1] = id + 1;
+        self.out_start.push(id + 1);
+        self.in_edges.push(id);
+        self.in_start.push(id + 1);
+        self.offsets.push(offset);
+        self.tokens.to_mut().push(token);
+        self.from.push(node);
+        self.to.push(node + 1);
+    }
+    pub fn from_tokens(tokens : &'a [Token]) -> TokenLattice<'a>
+    {
+        let n = tokens.len();
+        TokenLattice {
+            tokens : Cow::Borrowed(tokens),
+            from : (0..n).collect(),
+            to : (1..n + 1).collect(),
+            offsets : Vec::new(),
+            out_start : (0..n + 2).map(|i| i.min(n)).collect(),
+            in_start : (0..n + 2).map(|i| i.saturating_sub(1)).collect(),
+            in_edges : (0..n).collect(),
+        }
+    }
+    pub fn node_count(&self) -> usize { self.out_start.len() - 1 }
+    pub fn edges_from(&self, node : usize) -> std::ops::Range<usize> { self.out_start[node]..self.out_start[node + 1] }
+    pub fn edges_into(&self, node : usize) -> &[usize] { &self.in_edges[self.in_start[node]..self.in_start[node + 1]] }
+}
+
+pub fn skip_whitespace(s : &str, mut i : usize) -> usize
+{
+    while i < s.len() && get_char_at_byte(s, i).is_whitespace()
+    {
+        i += get_char_at_byte(s, i).len_utf8();
+    }
+    i
+}
+
+// Skips whitespace and the grammar's comments.
+pub fn skip_trivia(g : &Grammar, s : &str, mut i : usize) -> usize
+{
+    loop
+    {
+        i = skip_whitespace(s, i);
+        match g.comments.iter().filter_map(|r| r.find(&s[i..])).map(|m| m.len()).max()
+        {
+            Some(len) if len > 0 => i += len,
+            _ => return i,
+        }
+    }
+}
+
+// Every terminal that matches at the start of s, grouped by match length, shortest first.
+// Without the tokenizer DFA (or if it gives up), this tries each terminal one at a time.
+// Either way, a regex never matches more of s than its own leftmost-first search does.
+fn terminal_matches(cg : &CompiledGrammar, cache : &mut Option<regex_automata::hybrid::dfa::Cache>, s : &str) -> Vec<(usize, Vec<TermId>)>
+{
+    if let (Some(matcher), Some(cache)) = (&cg.token_matcher, cache.as_mut()) && let Ok(found) = matcher.all_matches(cache, s)
+    {
+        return found;
+    }
+    let mut by_len = std::collections::BTreeMap::<usize, Vec<TermId>>::new();
+    for (id, r) in cg.g.regexes.iter().enumerate()
+    {
+        if let Some(m) = r.find(s) && m.len() > 0 { by_len.entry(m.len()).or_default().push(TermId::Regex(id)); }
+    }
+    for (id, text) in cg.g.literals.iter().enumerate()
+    {
+        if !text.is_empty() && !is_layout_literal(text) && cg.g.literal_matches_at(id, s.as_bytes()) { by_len.entry(text.len()).or_default().push(TermId::Lit(id)); }
+    }
+    by_len.into_iter().collect()
+}
+
+// Whether any allowed terminal matches at the start of s. Cheaper than terminal_matches(), since it stops at the first match.
+fn terminal_matches_any(cg : &CompiledGrammar, cache : &mut Option<regex_automata::hybrid::dfa::Cache>, s : &str, allowed : impl Fn(&TermId) -> bool) -> bool
+{
+    if let (Some(matcher), Some(cache)) = (&cg.token_matcher, cache.as_mut()) && let Ok(any) = matcher.matches_any(cache, s, &allowed)
+    {
+        return any;
+    }
+    longest_allowed_match(&terminal_matches(cg, cache, s), allowed).is_some()
+}
+
+// The longest match (from terminal_matches()) that any allowed terminals are part of, with only the allowed terminals.
+fn longest_allowed_match(found : &[(usize, Vec<TermId>)], allowed : impl Fn(&TermId) -> bool) -> Option<(usize, Vec<TermId>)>
+{
+    found.iter().rev().find_map(|(len, terms)|
+    {
+        let terms = terms.iter().copied().filter(|t| allowed(t)).collect::<Vec<_>>();
+        (!terms.is_empty()).then_some((*len, terms))
+    })
+}
+
+fn decimal_value(text : &str) -> Option<u64>
+{
+    if text.is_empty() || !text.bytes().all(|b| b.is_ascii_digit()) { return None; }
+    text.parse().ok()
+}
+
+// The terminals of tokens with some text, and what's worked out from them (see Token).
+type TokenTerms = (Option<usize>, Arc<[usize]>, Option<Arc<[(TermId, u64)]>>, Option<Arc<[Arc<TokenCaptures>]>>);
+
+// The text as a decimal number, for each of the token's terminals (see Token::values).
+fn token_values(text : &str, lit : Option<usize>, regexes : &[usize]) -> Option<Arc<[(TermId, u64)]>>
+{
+    let value = decimal_value(text)?;
+    Some(lit.map(TermId::Lit).into_iter().chain(regexes.iter().map(|&id| TermId::Regex(id))).map(|term| (term, value)).collect())
+}
+
+// The capture groups of each of the token's regexes that has any (see TokenCaptures).
+fn token_captures(cg : &CompiledGrammar, text : &str, regexes : &[usize]) -> Option<Arc<[Arc<TokenCaptures>]>>
+{
+    let captures = regexes.iter().filter(|&&id| cg.regexes[id].regex().captures_len() > 1).filter_map(|&id|
+    {
+        let found = cg.regexes[id].regex().captures(text)?;
+        Some(Arc::new(TokenCaptures { regex : id, groups : found.iter().map(|m| m.map(|m| (m.start(), m.end()))).collect() }))
+    }).collect::<Vec<_>>();
+    if captures.is_empty() { None } else { Some(captures.into()) }
+}
+
+// regex_ids_of deduplicates the tokens' regex id lists, so that tokens matching the same regexes share them.
+// Regexes that exclude the token's literal (see Grammar::regex_exclusions) get left out.
+fn token_from_terms(cg : &CompiledGrammar, symbols : &mut Interner, regex_ids_of : &mut rustc_hash::FxHashMap<Vec<usize>, Arc<[usize]>>, text : &str, span : TokenSpan, terms : &[TermId]) -> Token
+{
+    let g = &cg.g;
+    let mut token = Token::new(symbols, text, terms);
+    token.span = span;
+    if token.regexes.iter().any(|&id| !g.regex_exclusions[id].is_empty())
+    {
+        // By text, not token.lit, since the contextual lexer leaves out terminals that the parser can't accept.
+        let lit = g.literal_id(text);
+        token.regexes = token.regexes.iter().copied().filter(|&id| !g.excludes(id, lit)).collect();
+    }
+    match regex_ids_of.get(&token.regexes[..])
+    {
+        Some(regexes) => token.regexes = Arc::clone(regexes),
+        None => { regex_ids_of.insert(token.regexes.to_vec(), Arc::clone(&token.regexes)); }
+    }
+    token.values = token_values(text, token.lit, &token.regexes);
+    token.captures = token_captures(cg, text, &token.regexes);
+    token
+}
+
+// Tokenizer for binary formats, or anything else that isn't UTF-8. Uses longest match, like tokenize(), but doesn't skip
+//  anything between tokens, since whitespace is probably data here. Byte terminals (see bnf_byte_pattern()) only match
+//  here, and literals and rx%...%rx regexes match here too.
+// Tokens are slices of the input (see Token::bytes()). Their text (for the AST) has anything but printable ASCII escaped, like \x7f.
+#[allow(unused)]
+pub fn tokenize_bytes(cg : &CompiledGrammar, symbols : &mut Interner, s : &[u8]) -> Result<Vec<Token>, String>
+{
+    let g = &cg.g;
+    let mut regex_ids_of = rustc_hash::FxHashMap::<Vec<usize>, Arc<[usize]>>::default();
+    let mut tokens = vec!();
+    let mut i = 0;
+    while i < s.len()
+    {
+        let rest = &s[i..];
+        let mut by_len = std::collections::BTreeMap::<usize, Vec<TermId>>::new();
+        for (id, r) in g.byte_regexes.iter().enumerate()
+        {
+            if let Some(m) = r.find(rest) && m.len() > 0 { by_len.entry(m.len()).or_default().push(TermId::Regex(id)); }
+        }
+        for (id, text) in g.literals.iter().enumerate()
+        {
+            if !text.is_empty() && !is_layout_literal(text) && g.literal_matches_at(id, rest) { by_len.entry(text.len()).or_default().push(TermId::Lit(id)); }
+        }
+        let Some((len, terms)) = by_len.pop_last() else
+        {
+            return Err(format!("Failed to tokenize at index {i}"));
+        };
+        let text = rest[..len].escape_ascii().to_string();
+        let mut token = token_from_terms(cg, symbols, &mut regex_ids_of, &text, TokenSpan::new(i, i + len), &terms);
+        // The text is escaped, so capture groups wouldn't line up with it.
+        token.captures = None;
+        // Byte terminals stand for the bytes as a number, and everything else for the text as a decimal number.
+        let is_byte_terminal = |term : &TermId| matches!(term, TermId::Regex(id) if g.regexes[*id].as_str() == NEVER_MATCH_PATTERN);
+        let bytes_value = (len <= 8).then(|| rest[..len].iter().fold(0, |n, b| n << 8 | *b as u64));
+        let text_value = std::str::from_utf8(&rest[..len]).ok().and_then(decimal_value);
+        let values = token.lit.map(TermId::Lit).into_iter().chain(token.regexes.iter().map(|&id| TermId::Regex(id)))
+            .filter_map(|term| Some((term, if is_byte_terminal(&term) { bytes_value? } else { text_value? })))
+            .collect::<Vec<_>>();
+        token.values = if values.is_empty() { None } else { Some(values.into()) };
+        tokens.push(token);
+        i += len;
+    }
+    Ok(tokens)
+}
+
+// Tokenizer for scannerless grammars (see ScannerlessDirectives): every character is a token, and nothing gets skipped,
+//  since the grammar's layout rule takes care of whitespace and comments.
+#[allow(unused)]
+pub fn tokenize_chars(cg : &CompiledGrammar, symbols : &mut Interner, s : &str) -> Result<Vec<Token>, String>
+{
+    let mut terms_of = HashMap::<char, Vec<TermId>>::new();
+    let mut regex_ids_of = rustc_hash::FxHashMap::<Vec<usize>, Arc<[usize]>>::default();
+    let mut tokens = vec!();
+    for (i, c) in s.char_indices()
+    {
+        let text = &s[i..i + c.len_utf8()];
+        let terms = terms_of.entry(c).or_insert_with(||
+        {
+            let lit = cg.g.literal_id(text).map(TermId::Lit);
+            lit.into_iter().chain(cg.regexes.iter().filter(|r| r.regex().is_match(text)).map(|r| TermId::Regex(r.id))).collect()
+        });
+        if terms.is_empty() { return Err(format!("Failed to tokenize at index {i}")); }
+        tokens.push(token_from_terms(cg, symbols, &mut regex_ids_of, text, TokenSpan::new(i, i + text.len()), terms));
+    }
+    Ok(tokens)
+}
+
+// Lexer for earley_parse_contextual(), which lexes one token at a time, only considering the terminals that the parser
+//  can accept next. That way, e.g. a contextual keyword gets lexed as an identifier wherever the keyword isn't allowed.
+pub struct ContextualLexer<'a> {
+    cg : &'a CompiledGrammar,
+    symbols : &'a mut Interner,
+    pub input : &'a str,
+    cache : Option<regex_automata::hybrid::dfa::Cache>,
+    regex_ids_of : rustc_hash::FxHashMap<Vec<usize>, Arc<[usize]>>,
+    // Set if nothing at all matched somewhere (not even terminals that weren't expected).
+    pub error : Option<String>,
+}
+
+impl<'a> ContextualLexer<'a> {
+    pub fn new(cg : &'a CompiledGrammar, symbols : &'a mut Interner, input : &'a str) -> ContextualLexer<'a>
+    {
+        ContextualLexer { cg, symbols, input, cache : cg.token_matcher.as_ref().map(|m| m.create_cache()), regex_ids_of : <_>::default(), error : None }
+    }
+    // Lexes the token at byte offset i: the longest match among the expected terminals, or if none of them match, the longest
+    //  match among every terminal, so that there's still a token to point at when reporting the parse error.
+    // Returns the token and where the next one starts. Returns None at the end of the input or if nothing matches (see error).
+    pub fn lex(&mut self, i : usize, expected : &HashSet<TermId>) -> Option<(Token, usize)>
+    {
+        if i >= self.input.len() { return None; }
+        let rest = &self.input[i..];
+        let found = terminal_matches(self.cg, &mut self.cache, rest);
+        let pick = longest_allowed_match(&found, |t| expected.contains(t)).or_else(|| found.last().cloned());
+        let Some((len, terms)) = pick else
+        {
+            self.error = Some(format!("Failed to tokenize at index {i}"));
+            return None;
+        };
+        let token = token_from_terms(self.cg, self.symbols, &mut self.regex_ids_of, &rest[..len], TokenSpan::new(i, i + len), &terms);
+        Some((token, skip_trivia(&self.cg.g, self.input, i + len)))
+    }
+}
+
+// Like tokenize(), but instead of committing to the longest match, every match at each position becomes an edge, and the
+//  parser picks between them. For example, with the literals ">" and ">>", the input ">>" becomes a lattice with both
+//  a ">>" edge and two ">" edges.
+// That includes the shorter matches of a single terminal, so an identifier regex also splits "abc" into "ab" and "c" (and
+//  "a", "b" and "c"). If your grammar can put two identifiers next to each other, it has to rule those splits out itself,
+//  e.g. with @nofollow.
+// Doesn't support lexer modes, since a mode stack needs a single path through the input.
+#[allow(unused)]
+pub fn tokenize_lattice(cg : &CompiledGrammar, symbols : &mut Interner, s : &str) -> Result<TokenLattice<'static>, String>
+{
+    if !cg.g.lexer_modes.is_empty() { return Err("tokenize_lattice doesn't support lexer modes".to_string()); }
+    let mut cache = cg.token_matcher.as_ref().map(|m| m.create_cache());
+    let mut regex_ids_of = rustc_hash::FxHashMap::<Vec<usize>, Arc<[usize]>>::default();
+    let skip_trivia = |i| skip_trivia(&cg.g, s, i);
+    
+    // Nodes get numbered in the order of their positions, which is also a topological order, since edges only go forwards.
+    let mut reachable = vec!(false; s.len() + 1);
+    reachable[skip_trivia(0)] = true;
+    let mut node_at = vec!(usize::MAX; s.len() + 1);
+    let mut offsets = Vec::new();
+    let mut edges = Vec::new();
+    let mut stuck_at = None;
+    for start in 0..=s.len()
+    {
+        if !reachable[start] { continue; }
+        node_at[start] = offsets.len();
+        offsets.push(start);
+        if start == s.len() { break; }
+        let rest = &s[start..];
+        
+        // One edge per match length, matching every terminal that matches exactly that much.
+        let found = terminal_matches(cg, &mut cache, rest);
+        if found.is_empty()
+        {
+            stuck_at = Some(start);
+            continue;
+        }
+        for (len, terms) in found
+        {
+            let end = skip_trivia(start + len);
+            reachable[end] = true;
+            edges.push((offsets.len() - 1, end, token_from_terms(cg, symbols, &mut regex_ids_of, &rest[..len], TokenSpan::new(start, start + len), &terms)));
+        }
+    }
+    // If we can't get to the end, report the furthest place we got stuck.
+    if !reachable[s.len()]
+    {
+        return Err(format!("Failed to tokenize at index {}", stuck_at.unwrap_or(0)));
+    }
+    for edge in edges.iter_mut()
+    {
+        edge.1 = node_at[edge.1];
+    }
+    Ok(TokenLattice::new(offsets.len(), edges, offsets))
+}
+
+pub fn find_nullables(g : &Grammar) -> HashSet<(usize, usize)>
+{
+    // Following from: https://cs.stackexchange.com/questions/164696/
+    
+    // Building the bipartite graph:
+    // An alternation is nullable once every one of its items is a nullable rule, and a rule is nullable
+    //  once any one of its alternations is nullable. So, for each alternation, count how many of its items
+    //  aren't known to be nullable yet, and for each rule, remember which alternations are waiting on it.
+    let mut rhs_to_lhs = HashMap::<usize, Vec<(usize, usize)>>::new();
+    let mut remaining = HashMap::<(usize, usize), usize>::new();
+    
+    let mut nullable = HashSet::new();
+    let mut nullable_rules = HashSet::new();
+    let mut worklist = Vec::new();
+    for rule in &g.points
+    {
+        for (alt_i, alt) in rule.forms.iter().enumerate()
+        {
+            let lhs = (rule.id, alt_i);
+            // Terminals always consume a token, so alternations with any terminals in them can never be nullable.
+            if alt.matching_terms.iter().any(|item| !matches!(item, MatchingTerm::Rule(_))) { continue; }
+            for item in &alt.matching_terms
+            {
+                if let MatchingTerm::Rule(child) = item
+                {
+                    rhs_to_lhs.entry(*child).or_default().push(lhs);
+                }
+            }
+            remaining.insert(lhs, alt.matching_terms.len());
+            // Initial population
+            if alt.matching_terms.len() == 0
+            {
+                nullable.insert(lhs);
+                if nullable_rules.insert(rule.id) { worklist.push(rule.id); }
+            }
+        }
+    }
+    
+    // Parent scanning
+    while let Some(child) = worklist.pop()
+    {
+        for parent in rhs_to_lhs.get(&child).into_iter().flatten()
+        {
+            let count = remaining.get_mut(parent).unwrap();
+            *count -= 1;
+            // if parent's count is now zero, this parent is now known to be nullable
+            if *count == 0
+            {
+                nullable.insert(*parent);
+                if nullable_rules.insert(parent.0) { worklist.push(parent.0); }
+            }
+        }
+    }
+    
+    nullable
+}
+
+
+// Terminals of a grammar, deduplicated and numbered, so that analysis results can refer to them by index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TermId {
+    Lit(usize),
+    Regex(usize),
+}
+
+// Which terminals something (a rule, or an alternation) can start with.
+#[derive(Debug, Clone, Default)]
+pub struct FirstSet {
+    pub lits : HashSet<usize>,
+    pub regexes : HashSet<usize>,
+    // Whether it can also match zero tokens, i.e. whether the thing after it can also supply the first token.
+    pub nullable : bool,
+}
+
+impl FirstSet {
+    // Returns whether anything new got added.
+    fn merge(&mut self, other : &FirstSet) -> bool
+    {
+        let old_len = self.lits.len() + self.regexes.len();
+        self.lits.extend(other.lits.iter().copied());
+        self.regexes.extend(other.regexes.iter().copied());
+        old_len != self.lits.len() + self.regexes.len()
+    }
+}
+
+// A grammar plus everything about it that only has to be figured out once, instead of once per parse.
+// Build it with compile_grammar() and pass it to tokenize(), chart_fill(), earley_parse(), etc.
+// It's Send + Sync, so one of these can be shared between every thread that's parsing with it.
+#[derive(Debug)]
+pub struct CompiledGrammar {
+    pub g : Grammar,
+    
+    // Rules that have at least one nullable alternation. Fx-hashed because chart_fill checks it in its hot loop.
+    pub nullables : rustc_hash::FxHashSet<usize>,
+    // Combined match-longest regex of every literal (see build_literal_regex).
+    pub literal_regex : Regex,
+    // Every terminal in one DFA, for tokenize(). None if it couldn't be built, in which case tokenize() uses the regexes.
+    pub token_matcher : Option<TokenMatcher>,
+    
+    // One per distinct regex pattern, indexed by regex id. (Literal ids index into g.literals.)
+    pub regexes : Vec<RegexCacher>,
+    
+    // Prediction closures: for each rule, every rule that gets predicted (directly or not) when it gets predicted.
+    // Includes the rule itself.
+    pub predictions : Vec<Vec<usize>>,
+    // FIRST sets, per rule and per alternation.
+    pub first_sets : Vec<FirstSet>,
+    pub alt_first_sets : Vec<Vec<FirstSet>>,
+}
+
+impl CompiledGrammar {
+    pub fn term_id(&self, mt : &MatchingTerm) -> Option<TermId>
+    {
+        match mt {
+            MatchingTerm::Rule(_) => None,
+            MatchingTerm::TermLit(id) => Some(TermId::Lit(*id)),
+            MatchingTerm::TermRegex(regex) => Some(TermId::Regex(regex.id)),
+        }
+    }
+}
+
+// Fails to compile if any of these stop being shareable between threads.
+const _ : () = {
+    fn assert_send_sync<T : Send + Sync>() {}
+    let _ = assert_send_sync::<CompiledGrammar>;
+    let _ = assert_send_sync::<Token>;
+    let _ = assert_send_sync::<crate::earley::ASTNode>;
+};
+
+pub fn compile_grammar(g : Grammar) -> CompiledGrammar
+{
+    let nullables = find_nullables(&g).iter().map(|x| x.0).collect();
+    let literal_regex = build_literal_regex(&g);
+    
+    // Every regex id, including the ones that only @nofollow/@reject use, which no rule's matching terms have.
+    // The parsing version of each regex is its tokenization version, anchored at the end too.
+    let regexes = g.regexes.iter().enumerate().map(|(id, r)|
+    {
+        RegexCacher::new(id, Regex::new(&format!("{}\\z", r.as_str())).expect("Anchoring a valid regex keeps it valid"))
+    }).collect::<Vec<_>>();
+    
+    let token_matcher = TokenMatcher::new(&g, &regexes).ok();
+    
+    let mut cg = CompiledGrammar {
+        g, nullables, literal_regex, token_matcher, regexes,
+        predictions : Vec::new(), first_sets : Vec::new(), alt_first_sets : Vec::new(),
+    };
+    
+    // Prediction closures.
+    // Predicting a rule predicts every rule that can appear at the start of one of its alternations,
+    //  or after a run of nullable rules at the start of one of its alternations.
+    for start in 0..cg.g.points.len()
+    {
+        let mut seen = HashSet::new();
+        let mut stack = vec!(start);
+        while let Some(id) = stack.pop()
+        {
+            if !seen.insert(id) { continue; }
+            for alt in &cg.g.points[id].forms
+            {
+                for mt in &alt.matching_terms
+                {
+                    let MatchingTerm::Rule(child) = mt else { break };
+                    stack.push(*child);
+                    if !cg.nullables.contains(child) { break; }
+                }
+            }
+        }
+        let mut closure = seen.into_iter().collect::<Vec<_>>();
+        closure.sort();
+        cg.predictions.push(closure);
+    }
+    
+    // FIRST sets. Keep merging alternations' FIRST sets into their rules' until nothing changes.
+    let alt_first = |cg : &CompiledGrammar, first_sets : &Vec<FirstSet>, alt : &Alternation| -> FirstSet
+    {
+        let mut ret = FirstSet::default();
+        for mt in &alt.matching_terms
+        {
+            match cg.term_id(mt)
+            {
+                Some(TermId::Lit(id)) => { ret.lits.insert(id); return ret; }
+                Some(TermId::Regex(id)) => { ret.regexes.insert(id); return ret; }
+                None =>
+                {
+                    let MatchingTerm::Rule(child) = mt else { unreachable!() };
+                    ret.merge(&first_sets[*child]);
+                    if !cg.nullables.contains(child) { return ret; }
+                }
+            }
+        }
+        ret.nullable = true;
+        ret
+    };
+    let mut first_sets = vec!(FirstSet::default(); cg.g.points.len());
+    let mut changed = true;
+    while changed
+    {
+        changed = false;
+        for rule in &cg.g.points
+        {
+            for alt in &rule.forms
+            {
+                let first = alt_first(&cg, &first_sets, alt);
+                first_sets[rule.id].nullable |= first.nullable;
+                changed |= first_sets[rule.id].merge(&first);
+            }
+        }
+    }
+    cg.alt_first_sets = cg.g.points.iter().map(|rule| rule.forms.iter().map(|alt| alt_first(&cg, &first_sets, alt)).collect()).collect();
+    cg.first_sets = first_sets;
+    
+    cg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn nullable_rules(grammar : &str) -> Vec<String>
+    {
+        let g = bnf_to_grammar(grammar).unwrap();
+        let mut names = find_nullables(&g).into_iter().map(|(rule, _)| g.points[rule].name.to_string()).collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        names
+    }
+    
+    #[test]
+    fn nullable_needs_one_nullable_alternation_per_child()
+    {
+        // B is nullable even though its first alternation isn't, so A is too.
+        assert_eq!(nullable_rules("S ::= A \"x\"\nA ::= B B\nB ::= \"b\" | #intentionally empty\n"), ["A", "B"]);
+    }
+    
+    #[test]
+    fn nullable_needs_no_terminals()
+    {
+        // A has a terminal in it, so it's never nullable, however nullable B is.
+        assert_eq!(nullable_rules("S ::= A \"x\"\nA ::= B \"y\"\nB ::= #intentionally empty\n"), ["B"]);
+    }
+    
+    #[test]
+    fn directives_split_on_any_whitespace()
+    {
+        assert_eq!(bnf_parse_comments("@comment\trx%//[^\\n]*%rx\nS ::= \"x\"\n").unwrap(), ["//[^\\n]*"]);
+        assert!(bnf_to_grammar("@comment\trx%//[^\\n]*%rx\nS ::= \"x\"\n").is_ok());
+        assert!(bnf_to_grammar("@commentary rx%x%rx\nS ::= \"x\"\n").is_err());
+    }
+    
+    #[test]
+    fn lexer_modes()
+    {
+        let grammar = concat!(
+            "S ::= Item | Item S\nItem ::= Name | Str | \"{\" S \"}\"\n",
+            "Str ::= \"\\\"\" Parts \"\\\"\"\nParts ::= Part | Part Parts\nPart ::= rx%[^\"$\\\\]+%rx | \"${\" Name \"}\"\n",
+            "Name ::= rx%[a-z]+%rx\n",
+            "@mode default ::= \"\\\"\" -> push string | \"{\" -> push default | \"}\" -> pop\n",
+            "@mode string noskip ::= rx%[^\"$\\\\]+%rx | \"${\" -> push default | \"\\\"\" -> pop\n",
+        );
+        let cg = compile_grammar(bnf_to_grammar(grammar).unwrap());
+        let mut symbols = Interner::default();
+        // The spaces inside the string are kept, and the x inside ${} is lexed as a Name again.
+        let tokens = tokenize(&cg, &mut symbols, "\"a ${x} b\"").unwrap();
+        let texts = tokens.iter().map(|t| symbols.text(t.text).as_str()).collect::<Vec<_>>();
+        assert_eq!(texts, ["\"", "a ", "${", "x", "}", " b", "\""]);
+        assert!(tokens[3].terms().eq([cg.g.terminal("Name").unwrap()]));
+        
+        assert_eq!(tokenize(&cg, &mut symbols, "x }").err().as_deref(), Some("Lexer mode popped with nothing to return to at index 2"));
+        assert_eq!(tokenize(&cg, &mut symbols, "\"a ${x").err().as_deref(), Some("Input ended in lexer mode default"));
+        assert_eq!(tokenize(&cg, &mut symbols, "\"a").err().as_deref(), Some("Input ended in lexer mode string"));
+    }
+    
+    #[test]
+    fn regex_match_table_past_64_regexes()
+    {
+        // Regex i matches "wi", and the last one matches all of them, so most tokens match two regexes in different words.
+        let names = (0..100).map(|i| format!("R{i}")).collect::<Vec<_>>();
+        let mut grammar = format!("S ::= T | T S\nT ::= {} | Any\nAny ::= rx%w[0-9]+%rx\n", names.join(" | "));
+        for i in 0..100
+        {
+            grammar += &format!("R{i} ::= rx%w{i}%rx\n");
+        }
+        let cg = compile_grammar(bnf_to_grammar(&grammar).unwrap());
+        assert!(cg.regexes.len() > 64);
+        let mut symbols = Interner::default();
+        let tokens = tokenize(&cg, &mut symbols, "w0 w63 w64 w65 w99 w100").unwrap();
+        assert!(tokens[1..5].iter().all(|t| t.regexes.len() == 2 && t.regexes.iter().any(|&id| id >= 64)));
+        let table = RegexMatchTable::new(&cg, &tokens);
+        for (i, token) in tokens.iter().enumerate()
+        {
+            for id in 0..cg.regexes.len()
+            {
+                assert_eq!(table.matches(i, id), token.regexes.contains(&id), "token {i}, regex {id}");
+            }
+        }
+    }
+    
+    #[test]
+    fn lattice_rejects_lexer_modes()
+    {
+        let cg = compile_grammar(bnf_to_grammar("S ::= \"x\"\n@mode default ::= \"x\"\n").unwrap());
+        assert!(tokenize_lattice(&cg, &mut Interner::default(), "x").is_err());
+    }
+    
+    #[test]
+    fn unrecognized_run_ends_where_a_terminal_starts()
+    {
+        let cg = compile_grammar(bnf_to_grammar("S ::= Name | Name S\nName ::= rx%[a-z]+%rx\n").unwrap());
+        let input = format!("ab {}cd", "?".repeat(100_000));
+        let (tokens, errors) = tokenize_recovering(&cg, &mut Interner::default(), &input, &TokenizeOptions::default());
+        assert_eq!(tokens.len(), 3);
+        assert_eq!((errors.len(), errors[0].start, errors[0].end), (1, 3, input.len() - 2));
+    }
+    
+    #[test]
+    fn lazy_regexes_stop_at_their_own_match()
+    {
+        let grammar = "S ::= Item | Item S\nItem ::= Comment | Str | Name\nComment ::= rx%/\\*.*?\\*/%rx\nStr ::= rx%\".*?\"%rx\nName ::= rx%[a-z]+%rx\n";
+        let cg = compile_grammar(bnf_to_grammar(grammar).unwrap());
+        assert!(cg.token_matcher.is_some());
+        let texts = |symbols : &Interner, tokens : &[Token]| tokens.iter().map(|t| symbols.text(t.text).to_string()).collect::<Vec<_>>();
+        let mut symbols = Interner::default();
+        let tokens = tokenize(&cg, &mut symbols, "/* a */ x /* b */").unwrap();
+        assert_eq!(texts(&symbols, &tokens), ["/* a */", "x", "/* b */"]);
+        let tokens = tokenize(&cg, &mut symbols, "\"a\" \"b\"").unwrap();
+        assert_eq!(texts(&symbols, &tokens), ["\"a\"", "\"b\""]);
+        let lattice = tokenize_lattice(&cg, &mut symbols, "\"a\" \"b\"").unwrap();
+        assert_eq!(texts(&symbols, &lattice.tokens), ["\"a\"", "\"b\""]);
+        
+        let cg = compile_grammar(bnf_to_grammar(&format!("{grammar}@mode default ::= rx%/\\*.*?\\*/%rx | rx%[a-z]+%rx\n")).unwrap());
+        let tokens = tokenize(&cg, &mut symbols, "/* a */ x /* b */").unwrap();
+        assert_eq!(texts(&symbols, &tokens), ["/* a */", "x", "/* b */"]);
+    }
+    
+    #[test]
+    fn indentation_tokens()
+    {
+        let cg = compile_grammar(bnf_to_grammar("S ::= Stmt | Stmt S\nStmt ::= Name NEWLINE | Name \":\" NEWLINE INDENT S DEDENT\nName ::= rx%[a-z]+%rx\n").unwrap());
+        let options = TokenizeOptions { indentation : true };
+        let mut symbols = Interner::default();
+        let tokens = tokenize_with_options(&cg, &mut symbols, "a:\n  b:\n    c\n\n  d\ne\n", &options).unwrap();
+        let texts = tokens.iter().map(|t| symbols.text(t.text).as_str()).collect::<Vec<_>>().join(" ");
+        assert_eq!(texts, "a : <NEWLINE> <INDENT> b : <NEWLINE> <INDENT> c <NEWLINE> <DEDENT> d <NEWLINE> <DEDENT> e <NEWLINE>");
+        assert!(crate::earley::earley_parse(&cg, "S", &symbols, &tokens).is_ok());
+        
+        let error = tokenize_with_options(&cg, &mut symbols, "a:\n    b\n  c\n", &options).unwrap_err();
+        assert!(error.starts_with("Inconsistent indentation on line 3"), "{error}");
+        let error = tokenize_with_options(&cg, &mut symbols, "  a\nb\n", &options).unwrap_err();
+        assert!(error.starts_with("Unexpected indentation on line 1"), "{error}");
+        let (_, errors) = tokenize_recovering(&cg, &mut symbols, "  a\nb\n", &options);
+        assert_eq!(errors.len(), 2);
+    }
+    
+    #[test]
+    fn restrictions_are_stored_directly()
+    {
+        // [a-f] isn't used by any rule, so it only gets an id for the restriction.
+        let g = bnf_to_grammar("S ::= Num \"x\"\nNum ::= rx%[0-9]+%rx\n@nofollow Num [a-f]\n").unwrap();
+        assert!(g.points.iter().all(|point| !point.name.starts_with('@')));
+        assert_eq!(g.restrictions[&g.by_name["Num"]].nofollow.len(), 1);
+        let cg = compile_grammar(bnf_to_grammar("S ::= Num Name\nNum ::= rx%[0-9]+%rx\nName ::= rx%[a-z]+%rx\n@nofollow Num [a-f]\n").unwrap());
+        let parses = |input : &str|
+        {
+            let mut symbols = Interner::default();
+            let lattice = tokenize_lattice(&cg, &mut symbols, input).unwrap();
+            crate::earley::earley_parse_lattice(&cg, "S", &symbols, &lattice, &<_>::default()).is_ok()
+        };
+        assert!(parses("12 xyz"));
+        assert!(!parses("12abc"));
+        let cg = compile_grammar(bnf_to_grammar("S ::= Num Name\nNum ::= rx%[0-9]+%rx\nName ::= rx%[a-z]+%rx\n@nofollow Num rxb%[a-f]%rxb\n").unwrap());
+        assert!(tokenize_bytes(&cg, &mut Interner::default(), b"12abc").is_ok());
+        assert!(bnf_to_grammar("@scannerless\nS ::= \"if\" [a-z]\n@nofollow \"if\" [a-z]\n").is_ok());
+        let error = bnf_to_grammar("S ::= \"if\" [a-z]\n@nofollow \"if\" [a-z]\n").unwrap_err();
+        assert!(error.contains("@scannerless"), "{error}");
+    }
+    
+    #[test]
+    fn regex_with_different_exclusions_is_ambiguous()
+    {
+        let grammar = "S ::= ident | name \"x\"\nident ::= rx%[a-z]+%rx - \"x\"\nname ::= rx%[a-z]+%rx\n@mode words ::= rx%[a-z]+%rx\n";
+        let mut g = bnf_to_grammar(grammar).unwrap();
+        assert_eq!(g.regex_terminals("rx%[a-z]+%rx").len(), 2);
+        assert_eq!(g.terminal("rx%[a-z]+%rx"), None);
+        assert_ne!(g.terminal("ident"), g.terminal("name"));
+        assert_eq!(g.lexer_modes[1].terms, g.regex_terminals("rx%[a-z]+%rx").into_iter().collect());
+        assert!(g.set_converter("rx%[a-z]+%rx", Converter::String).is_err());
+        assert!(g.set_converter("name", Converter::String).is_ok());
+    }
 }
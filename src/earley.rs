@@ -1,5 +1,6 @@
 //use std::collections::{HashMap, HashSet};
-use std::rc::Rc;
+use std::borrow::Cow;
+use std::sync::Arc;
 
 use rustc_hash::FxBuildHasher;
 type HashMap<K, V> = std::collections::HashMap::<K, V, FxBuildHasher>;
@@ -60,25 +61,258 @@ pub struct ChartData {
     chart : Vec<ChartColumn>,
     taildown : HashMap<(usize, usize), HashSet<usize>>,
     origin_sets : HashMap<(usize, usize), HashSet<usize>>,
+    // Kept around because AST building can add more items to the chart (see fix_missing_reductions).
+    regex_matches : RegexMatchTable,
+    // How many items the chart has, so that AST building can keep enforcing ParseLimits as it adds more.
+    total_items : usize,
+}
+
+// Where the setup for the right recursion hack happens.
+// Both versions accept the same inputs and build the same ASTs, but their charts aren't the same: they find different
+//  sets of right-recursive chains to skip over, so the items in the middle of those chains differ. (AST building fills in
+//  the ones it needs with fix_missing_reductions either way.)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RightRecursionMode {
+    // Tutorial version: set up tail returns during prediction, when we don't know if they'll ever be needed yet.
+    #[default]
+    AtPrediction,
+    // Completion-time version: find tail returns lazily during completion, by walking up the origin sets of the items
+    //  that are actually completing. Items that never complete never get any tail return data generated for them,
+    //  but the walking and memoizing cost about as much, so this isn't faster. (It's not the reduction-pointer version
+    //  that the README describes.)
+    AtCompletion,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EarleyOptions {
+    pub right_recursion : RightRecursionMode,
+    pub limits : ParseLimits,
+}
+
+// Hard ceilings for parsing untrusted input. Everything is unlimited by default.
+// A hostile input against an ambiguous grammar can make the chart very big; these make chart_fill give up instead.
+#[derive(Debug, Clone, Default)]
+pub struct ParseLimits {
+    pub max_column_items : Option<usize>,
+    pub max_total_items : Option<usize>,
+    // Most reduction pointers that a single item is allowed to have. Big sets mean lots of ambiguity.
+    pub max_reductions : Option<usize>,
+    pub deadline : Option<std::time::Instant>,
+    // Set this from another thread to make the parse give up.
+    pub cancel : Option<std::sync::Arc<std::sync::atomic::AtomicBool>>,
+}
+
+// Checking the clock and the cancellation flag isn't free, so only do it every this many chart items.
+pub(crate) const LIMIT_CHECK_INTERVAL : usize = 1024;
+
+impl ParseLimits {
+    pub(crate) fn check_sizes(&self, column_items : usize, total_items : usize) -> Result<(), ParseAbort>
+    {
+        if self.max_column_items.is_some_and(|max| column_items > max) { return Err(ParseAbort::TooManyColumnItems); }
+        if self.max_total_items.is_some_and(|max| total_items > max) { return Err(ParseAbort::TooManyTotalItems); }
+        Ok(())
+    }
+    pub(crate) fn check_time(&self) -> Result<(), ParseAbort>
+    {
+        if let Some(cancel) = &self.cancel && cancel.load(std::sync::atomic::Ordering::Relaxed) { return Err(ParseAbort::Cancelled); }
+        if self.deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline) { return Err(ParseAbort::DeadlineExceeded); }
+        Ok(())
+    }
+}
+
+// Why chart_fill gave up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseAbort {
+    TooManyColumnItems,
+    TooManyTotalItems,
+    TooManyReductions,
+    DeadlineExceeded,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EarleyError {
+    // The input doesn't match the grammar. Column where the parse died, and whether the root rule
+    //  did complete, just not at the end of the input.
+    NoParse(usize, bool),
+    // Gave up because of a ParseLimits limit. This says nothing about whether the input is valid.
+    Aborted(ParseAbort),
+    // Only from earley_parse_contextual(), which does its own tokenizing.
+    Tokenize(String),
+    // A leaf's text didn't convert (see Converter): where the token is, and why.
+    Convert(TokenSpan, String),
+    // The chart didn't hold what AST building expected it to. This is a bug, but it gets reported instead of panicking.
+    Internal(String),
+}
+
+// Completion-time version of "Setup for the right-recursion hack".
+// If the item at (col, row) would complete as soon as its last child completes, and the item it would then complete into
+//  would do the same, and so on, find the topmost item of that chain. Returns None if there's no chain to skip over.
+// The conditions checked here are exactly the ones that the prediction-time version checks, just evaluated later.
+// Memoized in tailret so that each item of a long right-recursive chain only gets walked over once.
+fn find_tailret_target(g : &Grammar, nullables : &HashSet<usize>, chart : &[ChartColumn],
+    origin_sets : &HashMap<(usize, usize), HashSet<usize>>, tailret : &mut HashMap<(usize, usize), Option<(usize, usize)>>,
+    col : usize, row : usize) -> Option<(usize, usize)>
+{
+    // The parent that (col, row) returns into if the hack applies to it.
+    let tail_parent = |(col, row) : (usize, usize)| -> Option<(usize, usize)>
+    {
+        let item = &chart[col][row];
+        let terms = &g.points[item.rule as usize].forms[item.alt as usize].matching_terms;
+        let is_nullable = match terms.get(item.pos as usize) {
+            Some(MatchingTerm::Rule(id)) => nullables.contains(id),
+            _ => return None,
+        };
+        if is_nullable || item.pos as usize + 1 != terms.len() { return None; }
+        let set = origin_sets.get(&(item.start, item.rule as usize))?;
+        if set.len() != 1 { return None; }
+        let parent_row = *set.iter().next().unwrap();
+        let parent = &chart[item.start][parent_row];
+        // Is this optimization definitely safe?
+        if parent.pos as usize + 1 == g.points[parent.rule as usize].forms[parent.alt as usize].matching_terms.len()
+            && !nullables.contains(&(parent.rule as usize)) && !completion_is_checked(g, parent)
+        {
+            return Some((item.start, parent_row));
+        }
+        None
+    };
+    
+    // Right-recursive chains can be as long as the input, so walk up them with a loop, not with recursion.
+    let mut chain = Vec::new();
+    let mut cur = (col, row);
+    let mut ret = loop
+    {
+        if let Some(&target) = tailret.get(&cur) { break target; }
+        match tail_parent(cur)
+        {
+            Some(parent) => { chain.push(cur); cur = parent; }
+            None => { tailret.insert(cur, None); break None; }
+        }
+    };
+    // Walk back down, giving each item of the chain the same target as its parent (or its parent itself).
+    while let Some(child) = chain.pop()
+    {
+        ret = Some(ret.unwrap_or(cur));
+        tailret.insert(child, ret);
+        cur = child;
+    }
+    ret
+}
+
+// Can something with this FIRST set start at one of the tokens starting at col? (No tokens = end of input.)
+pub fn first_set_allows(first : &FirstSet, regex_matches : &RegexMatchTable, lattice : &TokenLattice, col : usize) -> bool
+{
+    if first.nullable { return true; }
+    lattice.edges_from(col).any(|e|
+    {
+        if let Some(lit) = lattice.tokens[e].lit && first.lits.contains(&lit) { return true; }
+        first.regexes.iter().any(|&r| regex_matches.matches(e, r))
+    })
+}
+
+// Does this lattice edge's token match this terminal?
+pub fn edge_matches(mt : &MatchingTerm, lattice : &TokenLattice, regex_matches : &RegexMatchTable, e : usize) -> bool
+{
+    match mt {
+        // Tokens match terminals by id, not text, so tokens from other lexers work too (see Tokenizer).
+        MatchingTerm::TermLit(id) => lattice.tokens[e].lit == Some(*id),
+        MatchingTerm::TermRegex(regex) => regex_matches.matches(e, regex.id),
+        MatchingTerm::Rule(_) => false,
+    }
+}
+
+// @length: can parent advance past the construct at its dot position, which spans start..end? (See LengthCheck.)
+// Walks back to the length token through the first edge into each node, so for lattices, this assumes that the
+//  tokens right before a length-prefixed construct aren't ambiguous.
+pub fn length_allows(g : &Grammar, lattice : &TokenLattice, parent : &StateItem, start : usize, end : usize) -> bool
+{
+    if g.lengths.is_empty() { return true; }
+    let Some(check) = g.lengths.get(&(parent.rule as usize, parent.alt as usize, parent.pos as usize)) else { return true };
+    let mut node = start;
+    let mut edge = None;
+    for _ in 0..check.back
+    {
+        let Some(&e) = lattice.edges_into(node).first() else { return false };
+        node = lattice.from[e];
+        edge = Some(e);
+    }
+    // The length token's value as whichever terminal the length term is (or can be, if it's a rule).
+    let terms = match &g.points[parent.rule as usize].forms[parent.alt as usize].matching_terms[parent.pos as usize - check.back]
+    {
+        MatchingTerm::TermLit(id) => vec!(TermId::Lit(*id)),
+        MatchingTerm::TermRegex(regex) => vec!(TermId::Regex(regex.id)),
+        MatchingTerm::Rule(id) => g.single_terminals(*id).unwrap_or_default(),
+    };
+    let Some(value) = edge.and_then(|e| terms.iter().find_map(|&term| lattice.tokens[e].value(term))) else { return false };
+    let len = if !check.bytes { end - start } else if start == end { 0 } else
+    {
+        let (Some(first), Some(&last)) = (lattice.edges_from(start).next(), lattice.edges_into(end).first()) else { return false };
+        lattice.tokens[last].span.end - lattice.tokens[first].span.start
+    };
+    len as u64 == value
+}
+
+// Does advancing parent past the rule at its dot position have a check that the right recursion hack would skip over?
+// (A @length construct there, or a restriction on that rule; see length_allows() and restrictions_allow().)
+// The hack jumps straight from a completed item to the top of its chain, so it has to stop below any such parent.
+fn completion_is_checked(g : &Grammar, parent : &StateItem) -> bool
+{
+    if g.lengths.contains_key(&(parent.rule as usize, parent.alt as usize, parent.pos as usize)) { return true; }
+    match g.points[parent.rule as usize].forms[parent.alt as usize].matching_terms.get(parent.pos as usize)
+    {
+        Some(MatchingTerm::Rule(id)) => *id != parent.rule as usize && g.restrictions.contains_key(id),
+        _ => false,
+    }
+}
+
+// Does this lattice edge's token match this terminal? (edge_matches(), for TermIds.)
+fn edge_matches_term(term : TermId, lattice : &TokenLattice, regex_matches : &RegexMatchTable, e : usize) -> bool
+{
+    match term {
+        TermId::Lit(id) => lattice.tokens[e].lit == Some(id),
+        TermId::Regex(id) => regex_matches.matches(e, id),
+    }
+}
+
+// @nofollow and @reject: can parent advance past an item of this rule that spans start..end? (See Restriction.)
+// A rule's own recursion doesn't count, or e.g. number ::= number [0-9] with @nofollow number [0-9] couldn't get past its
+//  first digit.
+// Like length_allows(), this follows the first edge out of each node, so it's meant for flat token lists. And with
+//  earley_parse_contextual(), the next token doesn't exist yet when things complete, so @nofollow doesn't do anything.
+pub fn restrictions_allow(g : &Grammar, lattice : &TokenLattice, regex_matches : &RegexMatchTable, parent : &StateItem, rule : usize, start : usize, end : usize) -> bool
+{
+    if g.restrictions.is_empty() || parent.rule as usize == rule { return true; }
+    let Some(restriction) = g.restrictions.get(&rule) else { return true };
+    if lattice.edges_from(end).any(|e| restriction.nofollow.iter().any(|&term| edge_matches_term(term, lattice, regex_matches, e)))
+    {
+        return false;
+    }
+    !restriction.reject.iter().any(|terms|
+    {
+        let mut node = start;
+        terms.iter().all(|&term|
+        {
+            let Some(e) = lattice.edges_from(node).next().filter(|_| node < end) else { return false };
+            node = lattice.to[e];
+            edge_matches_term(term, lattice, regex_matches, e)
+        }) && node == end
+    })
 }
 
 // Prescan optimization: only add state items if they are not a scan that's going to immediately fail.
 // This reduces the total amount of Stuff that the chart filler needs to process, saving a bit of time.
-pub fn chart_add_if_not_invalid(g : &Grammar, tokens : &[Token], chart : &mut Vec<ChartColumn>, col : usize, item : StateItem) -> Option<usize>
+pub fn chart_add_if_not_invalid(g : &Grammar, lattice : &TokenLattice, regex_matches : &RegexMatchTable, chart : &mut Vec<ChartColumn>, col : usize, item : StateItem) -> Option<usize>
 {
-    if col > tokens.len() { return None; }
+    if col >= lattice.node_count() { return None; }
     let terms = &g.points[item.rule as usize].forms[item.alt as usize].matching_terms;
     let mut matched = true;
-    if (item.pos as usize) < terms.len() && col < tokens.len()
+    if (item.pos as usize) < terms.len() && !lattice.edges_from(col).is_empty()
     {
         let mt = &terms[item.pos as usize];
-        match mt {
-            // Strings are semi-interned by deduplicating them with an Rc table. Including grammar strings.
-            // This makes a ptr comparison equivalent to a string comparison.
-            MatchingTerm::TermLit(text) => matched = Rc::ptr_eq(&tokens[col].text, text),
-            MatchingTerm::TermRegex(regex) => matched = regex.is_match(&tokens[col].text),
-            _ => matched = true,
-        };
+        if !matches!(mt, MatchingTerm::Rule(_))
+        {
+            matched = lattice.edges_from(col).any(|e| edge_matches(mt, lattice, regex_matches, e));
+        }
     }
     if !matched { return None; }
     if col >= chart.len()
@@ -87,8 +321,19 @@ pub fn chart_add_if_not_invalid(g : &Grammar, tokens : &[Token], chart : &mut Ve
     }
     Some(chart[col].c.insert(item))
 }
-pub fn chart_fill(g : &Grammar, root_rule_name : &str, tokens : &[Token]) -> ChartData
+// Columns are lattice nodes. For a flat token list (see TokenLattice::from_tokens), that's the usual one column per token.
+pub fn chart_fill(cg : &CompiledGrammar, root_rule_name : &str, lattice : &TokenLattice, options : &EarleyOptions) -> Result<ChartData, ParseAbort>
+{
+    chart_fill_impl(cg, root_rule_name, &mut Cow::Borrowed(lattice), None, options)
+}
+// With a lexer, the lattice gets built as we go: once a column is done, the lexer gets told which terminals its items
+//  are waiting on, and lexes the token that starts there. (So the lattice has to be a flat one, with one node to start with.)
+fn chart_fill_impl(cg : &CompiledGrammar, root_rule_name : &str, lattice : &mut Cow<TokenLattice>, mut lexer : Option<&mut ContextualLexer>, options : &EarleyOptions) -> Result<ChartData, ParseAbort>
 {
+    let g = &cg.g;
+    let limits = &options.limits;
+    limits.check_time()?;
+    
     // The actual chart.
     let mut chart = vec!(ChartColumn::default());
     
@@ -100,7 +345,12 @@ pub fn chart_fill(g : &Grammar, root_rule_name : &str, tokens : &[Token]) -> Cha
     }
     
     // For preemptive nullable completion, we need to know what the nullables are.
-    let nullables = find_nullables(g).iter().map(|x| x.0).collect::<HashSet<_>>();
+    let nullables = &cg.nullables;
+    
+    // For both FIRST-set lookahead and scanning, we need to know which regexes each token matches.
+    let mut regex_matches = RegexMatchTable::new(cg, &lattice.tokens);
+    // For the lexer (if there is one): which terminals the current column is waiting on.
+    let mut expected = std::collections::HashSet::new();
     
     // Origin set, used to bypass the "linear scan" step of finding parents to advance when children complete.
     // (start col, rule) -> set(parent row)
@@ -111,17 +361,68 @@ pub fn chart_fill(g : &Grammar, root_rule_name : &str, tokens : &[Token]) -> Cha
     // Right recursion hack: This part lets up avoid creating quadratically many state items on right recursion.
     // Pointers from child (col, row) to parent (col, row), at time of prediction.
     let mut tailret : HashMap<(usize, usize), (usize, usize)> = <_>::default();
+    // Right recursion hack, completion-time version: same as above, but filled in lazily, and remembering failures too.
+    let mut tailret_lazy : HashMap<(usize, usize), Option<(usize, usize)>> = <_>::default();
     
     // IMPLEMENTATION NOTE: In an optimized implementation, the above hashmaps should be "per column", not global.
     // But for the sake of readability I've left them as global
     
+    // For enforcing limits.
+    let mut finished_items = 0;
+    let mut steps = 0;
+    
     let mut col = 0;
     let mut row = 0;
     while col < chart.len()
     {
+        // Items only ever get added to the current column and the ones that its tokens end at (just the next one, for a flat
+        //  token list), so these are the only ones that can have grown.
+        let next_lens = lattice.edges_from(col).map(|e| chart.get(lattice.to[e]).map(|x| x.c.len()).unwrap_or(0));
+        let (next_max, next_items) = next_lens.fold((0, 0), |(max, sum), len| (max.max(len), sum + len));
+        limits.check_sizes(chart[col].c.len().max(next_max), finished_items + chart[col].c.len() + next_items)?;
+        steps += 1;
+        if steps % LIMIT_CHECK_INTERVAL == 0 { limits.check_time()?; }
+        
         // End of this column? Go to the next one.
         if row >= chart[col].c.len()
         {
+            // Now that every item in this column is known, we know which terminals could come next, so this is when
+            //  the lexer (if there is one) gets to lex the next token.
+            if let Some(lexer) = lexer.as_deref_mut() && col + 1 == lattice.node_count()
+            {
+                expected.clear();
+                for item in &chart[col].c.v
+                {
+                    let terms = &g.points[item.rule as usize].forms[item.alt as usize].matching_terms;
+                    if let Some(term) = terms.get(item.pos as usize).and_then(|mt| cg.term_id(mt)) { expected.insert(term); }
+                }
+                if let Some((token, next)) = lexer.lex(lattice.offsets[col], &expected)
+                {
+                    regex_matches.push(&token);
+                    lattice.to_mut().push_token(token, next);
+                }
+            }
+            
+            // Scan. This waits until the end of the column so that the lexer (if any) has a chance to run first.
+            // Because of the prescan optimization (only adding scan items that aren't going to fail their scan phase),
+            //  we already know that scan items in the chart have to be valid for at least one of the tokens here,
+            //  so we check validity on the progressed version instead.
+            // With a flat token list, there's only ever one token to scan, and it always goes to the next column.
+            for row in 0..chart[col].c.len()
+            {
+                let item = chart[col][row].clone();
+                let terms = &g.points[item.rule as usize].forms[item.alt as usize].matching_terms;
+                let Some(mt) = terms.get(item.pos as usize) else { continue };
+                if let MatchingTerm::Rule(_) = mt { continue; }
+                for e in lattice.edges_from(col)
+                {
+                    if edge_matches(mt, lattice, &regex_matches, e)
+                    {
+                        chart_add_if_not_invalid(g, lattice, &regex_matches, &mut chart, lattice.to[e], item.clone_progressed());
+                    }
+                }
+            }
+            
             // Set up reduction pointers. These are necessary for disambiguation.
             // We do this here instead of during completion because handling nullable rules is a lot simpler this way.
             // If you want maximum performance instead: do it during completion, and also when preemptively completing nullables.
@@ -134,7 +435,13 @@ pub fn chart_fill(g : &Grammar, root_rule_name : &str, tokens : &[Token]) -> Cha
                 {
                     for parent_row in set
                     {
-                        if let Some(&new_row) = chart[col].c.s.get(&chart[item.start][*parent_row].clone_progressed())
+                        let parent = &chart[item.start][*parent_row];
+                        if !length_allows(g, lattice, parent, item.start, col)
+                            || !restrictions_allow(g, lattice, &regex_matches, parent, item.rule as usize, item.start, col)
+                        {
+                            continue;
+                        }
+                        if let Some(&new_row) = chart[col].c.s.get(&parent.clone_progressed())
                         {
                             let item = chart[col].c[row].clone();
                             let e = reductions.entry(new_row).or_insert_with(|| <_>::default());
@@ -142,11 +449,13 @@ pub fn chart_fill(g : &Grammar, root_rule_name : &str, tokens : &[Token]) -> Cha
                             if item.alt < prev_best { e.clear(); }
                             
                             if item.alt <= prev_best { e.insert(row); }
+                            if limits.max_reductions.is_some_and(|max| e.len() > max) { return Err(ParseAbort::TooManyReductions); }
                         }
                     }
                 }
             }
             chart[col].reductions = Box::new(reductions);
+            finished_items += chart[col].c.len();
             
             /*
             let mut reductions = HashMap::<usize, usize>::default();
@@ -197,11 +506,23 @@ pub fn chart_fill(g : &Grammar, root_rule_name : &str, tokens : &[Token]) -> Cha
             {
                 // Right recursion hack:
                 // The right recursion hack itself. ctrl+f: "Setup for the right-recursion hack"
-                if set.len() == 1 && let Some(tailret_target) = tailret.get(&(item.start, *set.iter().next().unwrap()))
+                // (It skips over the items in between, so it can't check their @length constructs or restrictions, so chains
+                //  stop below those.)
+                let only_parent = (set.len() == 1).then(|| (item.start, *set.iter().next().unwrap()));
+                let tailret_target = if let Some(parent) = only_parent.filter(|parent| !completion_is_checked(g, &chart[parent.0][parent.1]))
+                {
+                    match options.right_recursion
+                    {
+                        RightRecursionMode::AtPrediction => tailret.get(&parent).copied(),
+                        RightRecursionMode::AtCompletion =>
+                            find_tailret_target(g, nullables, &chart, &origin_sets, &mut tailret_lazy, parent.0, parent.1),
+                    }
+                } else { None };
+                if let Some(tailret_target) = tailret_target
                 {
                     let new_item = chart[tailret_target.0][tailret_target.1].clone_progressed();
                     
-                    if let Some(new_row) = chart_add_if_not_invalid(g, tokens, &mut chart, col, new_item)
+                    if let Some(new_row) = chart_add_if_not_invalid(g, lattice, &regex_matches, &mut chart, col, new_item)
                     {
                         // Without these, we would be unable to reconstruct which items returned to which.
                         taildown.entry((col, new_row)).or_insert_with(|| <_>::default()).insert(row);
@@ -212,17 +533,24 @@ pub fn chart_fill(g : &Grammar, root_rule_name : &str, tokens : &[Token]) -> Cha
                 // Normal completion.
                 for parent_row in set
                 {
-                    let new_item = chart[item.start][*parent_row].clone_progressed();
-                    chart_add_if_not_invalid(g, tokens, &mut chart, col, new_item);
+                    let parent = &chart[item.start][*parent_row];
+                    if !length_allows(g, lattice, parent, item.start, col)
+                        || !restrictions_allow(g, lattice, &regex_matches, parent, item.rule as usize, item.start, col)
+                    {
+                        continue;
+                    }
+                    let new_item = parent.clone_progressed();
+                    chart_add_if_not_invalid(g, lattice, &regex_matches, &mut chart, col, new_item);
                 }
             }
         }
-        else if col <= tokens.len()
+        else
         {
             let mt = &terms[item.pos as usize];
             // Prediction
             if let MatchingTerm::Rule(id) = mt
             {
+                let lookahead = lexer.is_none() || col + 1 < lattice.node_count();
                 let rule = &g.points[*id as usize];
                 origin_sets.entry((col, *id)).or_insert_with(|| <_>::default()).insert(row);
                 let is_nullable = nullables.contains(id);
@@ -230,30 +558,39 @@ pub fn chart_fill(g : &Grammar, root_rule_name : &str, tokens : &[Token]) -> Cha
                 // Prediction itself.
                 for i in 0..rule.forms.len()
                 {
+                    // FIRST-set lookahead: don't predict alternations that can't start with the current token.
+                    // This is the same idea as the prescan optimization, but it can see through nonterminals.
+                    // (When lexing as we go, the current token doesn't exist yet, so we can't do this.)
+                    if lookahead && !first_set_allows(&cg.alt_first_sets[*id][i], &regex_matches, lattice, col)
+                    {
+                        continue;
+                    }
                     let new_item = StateItem { rule : *id as u32, alt : i as u16, pos : 0, start : col };
-                    chart_add_if_not_invalid(g, tokens, &mut chart, col, new_item);
+                    chart_add_if_not_invalid(g, lattice, &regex_matches, &mut chart, col, new_item);
                 }
                 
                 // For nullables, preemptively perform their completion.
                 // This addresses an operation ordering edge case that breaks grammars like:
                 //     program ::= B B "a"
                 //     A ::= #intentionally empty
-                if is_nullable
+                if is_nullable && length_allows(g, lattice, &item, col, col) && restrictions_allow(g, lattice, &regex_matches, &item, *id, col, col)
                 {
-                    chart_add_if_not_invalid(g, tokens, &mut chart, col, item.clone_progressed());
+                    chart_add_if_not_invalid(g, lattice, &regex_matches, &mut chart, col, item.clone_progressed());
                 }
                 
                 // Right recursion hack setup:
                 // Setup for the right-recursion hack: if the items produced by this prediction would cause US to complete ...
                 // ... set up a summarized upwards return sequence for them.
-                if !is_nullable && item.pos as usize + 1 == terms.len()
+                // (Only if the origin set we're looking at is finished, i.e. not in the current column.)
+                if options.right_recursion == RightRecursionMode::AtPrediction
+                    && !is_nullable && item.pos as usize + 1 == terms.len() && item.start < col
                     && let Some(set) = origin_sets.get(&(item.start, item.rule as usize)) && set.len() == 1
                 {
                     let parent_row = set.iter().next().unwrap();
                     let parent = chart[item.start][*parent_row].clone();
                     // Is this optimization definitely safe?
                     if parent.pos as usize + 1 == g.points[parent.rule as usize].forms[parent.alt as usize].matching_terms.len()
-                        && !nullables.contains(&(parent.rule as usize))
+                        && !nullables.contains(&(parent.rule as usize)) && !completion_is_checked(g, &parent)
                     {
                         let mut tailret_target = (item.start, *parent_row);
                         tailret_target = *tailret.get(&tailret_target).unwrap_or(&tailret_target);
@@ -262,24 +599,30 @@ pub fn chart_fill(g : &Grammar, root_rule_name : &str, tokens : &[Token]) -> Cha
                     }
                 }
             }
-            // Scan
-            else if col < tokens.len()
-            {
-                // Because of the prescan optimization (only adding scan items that aren't going to fail their scan phase),
-                //  we already know that scan items in the chart have to be valid, so we check validity on the progressed version instead.
-                chart_add_if_not_invalid(g, tokens, &mut chart, col + 1, item.clone_progressed());
-            }
+            // (Scans happen at the end of the column.)
         }
         row += 1;
     }
     
-    ChartData { chart, taildown, origin_sets }
+    let total_items = chart.iter().map(|column| column.c.len()).sum();
+    Ok(ChartData { chart, taildown, origin_sets, regex_matches, total_items })
 }
 
 #[allow(unused)]
-pub fn earley_recognize(g : &Grammar, root_rule_name : &str, tokens : &[Token]) -> Result<u16, (usize, bool)>
+pub fn earley_recognize(cg : &CompiledGrammar, root_rule_name : &str, tokens : &[Token]) -> Result<u16, EarleyError>
+{
+    earley_recognize_with_options(cg, root_rule_name, tokens, &EarleyOptions::default())
+}
+#[allow(unused)]
+pub fn earley_recognize_with_options(cg : &CompiledGrammar, root_rule_name : &str, tokens : &[Token], options : &EarleyOptions) -> Result<u16, EarleyError>
+{
+    earley_recognize_lattice(cg, root_rule_name, &TokenLattice::from_tokens(tokens), options)
+}
+#[allow(unused)]
+pub fn earley_recognize_lattice(cg : &CompiledGrammar, root_rule_name : &str, lattice : &TokenLattice, options : &EarleyOptions) -> Result<u16, EarleyError>
 {
-    let data = chart_fill(g, root_rule_name, tokens);
+    let g = &cg.g;
+    let data = chart_fill(cg, root_rule_name, lattice, options).map_err(EarleyError::Aborted)?;
     let chart = &data.chart;
     
     let root_id = g.by_name[root_rule_name];
@@ -289,21 +632,64 @@ pub fn earley_recognize(g : &Grammar, root_rule_name : &str, tokens : &[Token])
         let expected = StateItem { rule : root_id as u32, alt : i as u16, pos : pos as u16, start : 0 };
         if chart.last().unwrap().c.s.contains_key(&expected)
         {
-            if chart.len() != tokens.len() + 1 { return Err((chart.len(), true)); }
+            if chart.len() != lattice.node_count() { return Err(EarleyError::NoParse(chart.len(), true)); }
             return Ok(i as u16);
         }
     }
-    Err((chart.len(), false))
+    Err(EarleyError::NoParse(chart.len(), false))
+}
+
+// What an AST node stands for: a rule (for nonterminals) or the token's interned text (for terminals).
+// Use resolve() to get the actual text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ASTText {
+    Rule(u32),
+    Token(SymbolId),
+}
+
+impl ASTText {
+    #[allow(unused)]
+    // symbols is the Interner that the tokens were tokenized with.
+    pub fn resolve(&self, g : &Grammar, symbols : &Interner) -> Arc<String>
+    {
+        match self {
+            ASTText::Rule(id) => Arc::clone(&g.points[*id as usize].name),
+            ASTText::Token(sym) => Arc::clone(symbols.text(*sym)),
+        }
+    }
 }
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug)]
 pub struct ASTNode {
     #[allow(unused)]
-    pub text : Rc<String>,
+    pub text : ASTText,
     pub children : Option<Vec<Box<ASTNode>>>,
     pub token_start : usize,
     #[allow(unused)]
     pub token_count : usize,
+    // For leaves that matched a regex terminal with capture groups: where they matched (see ASTNode::capture).
+    pub captures : Option<Arc<TokenCaptures>>,
+    // For leaves that matched a terminal with a converter: their text, converted (see Converter).
+    #[allow(unused)]
+    pub converted : Option<Arc<LeafValue>>,
+}
+
+impl ASTNode {
+    // The text of capture group i of the regex terminal that this leaf matched, if the group took part in the match.
+    // text is the leaf's text (see ASTText::resolve).
+    #[allow(unused)]
+    pub fn capture<'a>(&self, text : &'a str, i : usize) -> Option<&'a str>
+    {
+        let (start, end) = (*self.captures.as_ref()?.groups.get(i)?)?;
+        Some(&text[start..end])
+    }
+    // Same, but for a named group, like (?<major>[0-9]+).
+    #[allow(unused)]
+    pub fn named_capture<'a>(&self, cg : &CompiledGrammar, text : &'a str, name : &str) -> Option<&'a str>
+    {
+        let regex = cg.regexes[self.captures.as_ref()?.regex].regex();
+        self.capture(text, regex.capture_names().position(|n| n == Some(name))?)
+    }
 }
 
 // ASTs can be deeply recursive, so we need to avoid destroying them recursively.
@@ -327,8 +713,74 @@ impl Drop for ASTNode {
     }
 }
 
-pub fn fix_missing_reductions(g : &Grammar, tokens : &[Token], data : &mut ChartData, col : usize, row : usize)
+// A lossless (concrete syntax tree) view of an AST, for tools like formatters that have to keep the input's whitespace and
+//  comments. Leaves own the trivia around their tokens (see TokenSpan), so the full_text() of the root, or of all the
+//  leaves put together in order, is the whole input, byte for byte. (As long as there's at least one token; otherwise
+//  there's nothing for the input's trivia to belong to.)
+// tokens and input have to be the ones that the AST was parsed from. Doesn't work for ASTs from lattices.
+#[derive(Clone, Copy, Debug)]
+#[allow(unused)]
+pub struct CSTView<'a> {
+    pub node : &'a ASTNode,
+    pub tokens : &'a [Token],
+    pub input : &'a str,
+}
+
+#[allow(unused)]
+impl<'a> CSTView<'a> {
+    pub fn new(node : &'a ASTNode, tokens : &'a [Token], input : &'a str) -> CSTView<'a>
+    {
+        CSTView { node, tokens, input }
+    }
+    pub fn children(&self) -> impl Iterator<Item = CSTView<'a>> + 'a
+    {
+        let (tokens, input) = (self.tokens, self.input);
+        self.node.children.iter().flatten().map(move |child| CSTView::new(child, tokens, input))
+    }
+    // The token, if this is a leaf.
+    pub fn token(&self) -> Option<&'a Token>
+    {
+        if self.node.children.is_some() { return None; }
+        self.tokens.get(self.node.token_start)
+    }
+    // From the start of the leading trivia of the first token to the end of the trailing trivia of the last one.
+    fn span(&self) -> TokenSpan
+    {
+        let tokens = &self.tokens[self.node.token_start..self.node.token_start + self.node.token_count];
+        match (tokens.first(), tokens.last())
+        {
+            (Some(first), Some(last)) => TokenSpan { leading : first.span.leading, start : first.span.start, end : last.span.end, trailing : last.span.trailing },
+            // Nothing but nullable rules, so there's nothing to put in it.
+            _ => TokenSpan::default(),
+        }
+    }
+    // Without the trivia before the first token and after the last one.
+    pub fn text(&self) -> &'a str
+    {
+        let span = self.span();
+        &self.input[span.start..span.end]
+    }
+    pub fn leading_trivia(&self) -> &'a str
+    {
+        let span = self.span();
+        &self.input[span.leading..span.start]
+    }
+    pub fn trailing_trivia(&self) -> &'a str
+    {
+        let span = self.span();
+        &self.input[span.end..span.trailing]
+    }
+    pub fn full_text(&self) -> &'a str
+    {
+        let span = self.span();
+        &self.input[span.leading..span.trailing]
+    }
+}
+
+// This adds items to the chart, so it enforces the size limits too.
+pub fn fix_missing_reductions(g : &Grammar, lattice : &TokenLattice, data : &mut ChartData, limits : &ParseLimits, col : usize, row : usize) -> Result<(), ParseAbort>
 {
+    let target_row = row;
     if let Some(bottoms) = data.taildown.get(&(col, row))
     {
         // Find the bottom of the tailcall.
@@ -336,13 +788,17 @@ pub fn fix_missing_reductions(g : &Grammar, tokens : &[Token], data : &mut Chart
         {
             let mut item = data.chart[col][*bottom].clone();
             let mut row = *bottom;
-            // Work our way up, generating each reduction pointer as we go.
-            while let Some(set) = data.origin_sets.get(&(item.start, item.rule as usize)) && set.len() == 1
+            // Work our way up, generating each reduction pointer as we go, until we're back at the item we're fixing.
+            // (Without that stopping point, this would loop forever on left-recursive roots, whose origin sets point to themselves.)
+            while row != target_row && let Some(set) = data.origin_sets.get(&(item.start, item.rule as usize)) && set.len() == 1
             {
                 for parent_row in set
                 {
                     let new_parent = data.chart[item.start][*parent_row].clone_progressed();
-                    let new_row = chart_add_if_not_invalid(g, tokens, &mut data.chart, col, new_parent).unwrap();
+                    let old_len = data.chart[col].c.len();
+                    let new_row = chart_add_if_not_invalid(g, lattice, &data.regex_matches, &mut data.chart, col, new_parent).unwrap();
+                    data.total_items += data.chart[col].c.len() - old_len;
+                    limits.check_sizes(data.chart[col].c.len(), data.total_items)?;
                     
                     let chart = &mut data.chart[col];
                     let e = chart.reductions.entry(new_row).or_insert_with(|| <_>::default());
@@ -350,6 +806,7 @@ pub fn fix_missing_reductions(g : &Grammar, tokens : &[Token], data : &mut Chart
                     if item.alt < prev_best { e.clear(); }
                     
                     if item.alt <= prev_best { e.insert(row); }
+                    if limits.max_reductions.is_some_and(|max| e.len() > max) { return Err(ParseAbort::TooManyReductions); }
                     /*
                     // FIXME: do actual disambiguation
                     data.chart[col].reductions.insert(new_row, row);
@@ -360,12 +817,13 @@ pub fn fix_missing_reductions(g : &Grammar, tokens : &[Token], data : &mut Chart
             }
         }
     }
+    Ok(())
 }
 
-pub fn build_ast_node(g : &Grammar, tokens : &[Token], data : &mut ChartData, col : usize, row : usize) -> Box<ASTNode>
+pub fn build_ast_node(g : &Grammar, symbols : &Interner, lattice : &TokenLattice, data : &mut ChartData, limits : &ParseLimits, col : usize, row : usize) -> Result<Box<ASTNode>, EarleyError>
 {
     struct ASTBuilderData<'a> {
-        children : Vec<Box<ASTNode>>, name : Rc<String>, gp_alt : &'a Alternation,
+        children : Vec<Box<ASTNode>>, name : ASTText, gp_alt : &'a Alternation,
         col_start : usize, col : usize, row : usize, pos : usize, pos_limit : usize,
     }
     
@@ -374,7 +832,7 @@ pub fn build_ast_node(g : &Grammar, tokens : &[Token], data : &mut ChartData, co
     
     // Current building context.
     let mut ctx = ASTBuilderData {
-        children : Vec::new(), name : Rc::clone(&gp.name), gp_alt : &gp.forms[base_item.alt as usize],
+        children : Vec::new(), name : ASTText::Rule(gp.id as u32), gp_alt : &gp.forms[base_item.alt as usize],
         col_start : col, col : col, row : row, pos : 0, pos_limit : base_item.pos as usize,
     };
     
@@ -382,15 +840,22 @@ pub fn build_ast_node(g : &Grammar, tokens : &[Token], data : &mut ChartData, co
     let mut stash : Vec<ASTBuilderData> = Vec::new();
     let mut prepared_child : Option<Box<ASTNode>> = None;
     
+    // For enforcing limits. (ASTs are as big as the input, so the deadline and cancellation still matter here.)
+    let mut steps = 0;
+    let mut converter = LeafConverter::default();
+    
     // As long as we haven't reached the end of the rootmost node...
     while !(ctx.pos == ctx.pos_limit && stash.len() == 0)
     {
+        steps += 1;
+        if steps % LIMIT_CHECK_INTERVAL == 0 { limits.check_time().map_err(EarleyError::Aborted)?; }
+        
         // If we're at the end of a lazily-dispatched child, prepare it and unstash the parent.
         if ctx.pos == ctx.pos_limit && stash.len() > 0
         {
             ctx.children.reverse();
             prepared_child = Some(Box::new(ASTNode {
-                text : Rc::clone(&ctx.name), children : Some(ctx.children), token_start : ctx.col, token_count : ctx.col_start - ctx.col,
+                text : ctx.name, children : Some(ctx.children), token_start : ctx.col, token_count : ctx.col_start - ctx.col, captures : None, converted : None,
             }));
             ctx = stash.pop().unwrap();
             continue;
@@ -402,7 +867,7 @@ pub fn build_ast_node(g : &Grammar, tokens : &[Token], data : &mut ChartData, co
         {
             // We need to fix right-recursion reductions at the last possible opportunity (i.e. now).
             // If any earlier, the chart gets bloated.
-            fix_missing_reductions(g, tokens, data, ctx.col, ctx.row);
+            fix_missing_reductions(g, lattice, data, limits, ctx.col, ctx.row).map_err(EarleyError::Aborted)?;
             
             // For now we arbitrarily pick whichever reduction is in the front.
             let child_row = *data.chart[ctx.col].reductions.get(&ctx.row).unwrap().iter().next().unwrap();
@@ -412,7 +877,7 @@ pub fn build_ast_node(g : &Grammar, tokens : &[Token], data : &mut ChartData, co
             let gp = &g.points[child_item.rule as usize];
             
             let next_data = ASTBuilderData {
-                children : Vec::new(), name : Rc::clone(&gp.name), gp_alt : &gp.forms[child_item.alt as usize],
+                children : Vec::new(), name : ASTText::Rule(gp.id as u32), gp_alt : &gp.forms[child_item.alt as usize],
                 col_start : ctx.col, col : ctx.col, row : child_row, pos : 0, pos_limit : child_item.pos as usize,
             };
             stash.push(ctx);
@@ -433,12 +898,24 @@ pub fn build_ast_node(g : &Grammar, tokens : &[Token], data : &mut ChartData, co
                 ctx.col = prepared_child.as_ref().unwrap().token_start;
                 ctx.children.push(prepared_child.take().unwrap());
             }
-            MatchingTerm::TermLit(_) | MatchingTerm::TermRegex(_) =>
+            mt @ (MatchingTerm::TermLit(_) | MatchingTerm::TermRegex(_)) =>
             {
-                // If it's a terminal, generate it directly.
-                ctx.col -= 1;
+                // If it's a terminal, generate it directly, from whichever token got the parent here.
+                // (With a flat token list, that's just the previous token. With a lattice, there might be several candidates.)
+                let edge = *lattice.edges_into(ctx.col).iter().find(|&&e|
+                    edge_matches(mt, lattice, &data.regex_matches, e) && data.chart[lattice.from[e]].c.s.contains_key(&prev_parent_item)
+                ).unwrap();
+                ctx.col = lattice.from[edge];
+                let token = &lattice.tokens[edge];
+                let (term, captures) = match mt
+                {
+                    MatchingTerm::TermRegex(regex) => (TermId::Regex(regex.id), token.captures_for(regex.id)),
+                    MatchingTerm::TermLit(id) => (TermId::Lit(*id), None),
+                    MatchingTerm::Rule(_) => unreachable!(),
+                };
+                let converted = converter.convert(g, symbols, token, term).map_err(|e| EarleyError::Convert(token.span, e))?;
                 ctx.children.push(Box::new(ASTNode {
-                    text : Rc::clone(&tokens[ctx.col].text), children : None, token_start : ctx.col, token_count : 1
+                    text : ASTText::Token(token.text), children : None, token_start : ctx.col, token_count : lattice.to[edge] - ctx.col, captures, converted,
                 }));
             }
         }
@@ -447,16 +924,58 @@ pub fn build_ast_node(g : &Grammar, tokens : &[Token], data : &mut ChartData, co
     }
     
     ctx.children.reverse();
-    Box::new(ASTNode {
-        text : Rc::clone(&ctx.name), children : Some(ctx.children),
-        token_start : ctx.col, token_count : ctx.col_start - ctx.col,
-    })
+    Ok(Box::new(ASTNode {
+        text : ctx.name, children : Some(ctx.children),
+        token_start : ctx.col, token_count : ctx.col_start - ctx.col, captures : None, converted : None,
+    }))
 }
 
+// symbols is the Interner that the tokens were tokenized with, for converting leaves (see Converter).
+#[allow(unused)]
+pub fn earley_parse(cg : &CompiledGrammar, root_rule_name : &str, symbols : &Interner, tokens : &[Token]) -> Result<Box<ASTNode>, EarleyError>
+{
+    earley_parse_with_options(cg, root_rule_name, symbols, tokens, &EarleyOptions::default())
+}
 #[allow(unused)]
-pub fn earley_parse(g : &Grammar, root_rule_name : &str, tokens : &[Token]) -> Result<Box<ASTNode>, (usize, bool)>
+pub fn earley_parse_with_options(cg : &CompiledGrammar, root_rule_name : &str, symbols : &Interner, tokens : &[Token], options : &EarleyOptions) -> Result<Box<ASTNode>, EarleyError>
 {
-    let mut data = chart_fill(g, root_rule_name, tokens);
+    earley_parse_lattice(cg, root_rule_name, symbols, &TokenLattice::from_tokens(tokens), options)
+}
+// Token positions in the resulting AST (token_start, token_count) are lattice nodes.
+#[allow(unused)]
+pub fn earley_parse_lattice(cg : &CompiledGrammar, root_rule_name : &str, symbols : &Interner, lattice : &TokenLattice, options : &EarleyOptions) -> Result<Box<ASTNode>, EarleyError>
+{
+    let data = chart_fill(cg, root_rule_name, lattice, options).map_err(EarleyError::Aborted)?;
+    parse_from_chart(cg, root_rule_name, symbols, lattice, data, &options.limits)
+}
+
+// Tokenizes and parses at the same time: the token at each position is the longest match among the terminals that the
+//  parser can accept there. This handles contextual keywords (e.g. "async" being an identifier wherever the "async"
+//  keyword isn't allowed) without having to go all the way to tokenize_lattice().
+// The catch is that FIRST-set lookahead can't be used (the next token isn't known while predicting), so this is slower.
+// Returns the tokens it lexed (as a flat lattice with byte offsets, so parse errors can be pointed at) alongside the result.
+// Doesn't support lexer modes, since the parser picks the terminals, not the mode stack.
+#[allow(unused)]
+pub fn earley_parse_contextual(cg : &CompiledGrammar, root_rule_name : &str, symbols : &mut Interner, input : &str, options : &EarleyOptions) -> (TokenLattice<'static>, Result<Box<ASTNode>, EarleyError>)
+{
+    let lattice = TokenLattice::new(1, Vec::new(), vec!(skip_trivia(&cg.g, input, 0)));
+    if !cg.g.lexer_modes.is_empty()
+    {
+        return (lattice, Err(EarleyError::Tokenize("earley_parse_contextual doesn't support lexer modes".to_string())));
+    }
+    let mut lexer = ContextualLexer::new(cg, symbols, input);
+    let mut lattice = Cow::Owned(lattice);
+    let data = chart_fill_impl(cg, root_rule_name, &mut lattice, Some(&mut lexer), options);
+    let mut lattice = lattice.into_owned();
+    assign_trivia(input, lattice.tokens.to_mut());
+    if let Some(error) = lexer.error { return (lattice, Err(EarleyError::Tokenize(error))); }
+    let ret = data.map_err(EarleyError::Aborted).and_then(|data| parse_from_chart(cg, root_rule_name, symbols, &lattice, data, &options.limits));
+    (lattice, ret)
+}
+
+fn parse_from_chart(cg : &CompiledGrammar, root_rule_name : &str, symbols : &Interner, lattice : &TokenLattice, mut data : ChartData, limits : &ParseLimits) -> Result<Box<ASTNode>, EarleyError>
+{
+    let g = &cg.g;
     let chart = &data.chart;
     
     let root_id = g.by_name[root_rule_name];
@@ -467,7 +986,7 @@ pub fn earley_parse(g : &Grammar, root_rule_name : &str, tokens : &[Token]) -> R
         let expected = StateItem { rule : root_id as u32, alt : i as u16, pos : pos as u16, start : 0 };
         if chart.last().unwrap().c.s.contains_key(&expected)
         {
-            if chart.len() != tokens.len() + 1 { return Err((chart.len(), true)); }
+            if chart.len() != lattice.node_count() { return Err(EarleyError::NoParse(chart.len(), true)); }
             chosen = Some(expected);
             break;
         }
@@ -476,7 +995,336 @@ pub fn earley_parse(g : &Grammar, root_rule_name : &str, tokens : &[Token]) -> R
     {
         let chosen_col = chart.len() - 1;
         let chosen_row = *chart[chosen_col].c.s.get(&chosen).unwrap();
-        return Ok(build_ast_node(g, tokens, &mut data, chosen_col, chosen_row));
+        return build_ast_node(g, symbols, lattice, &mut data, limits, chosen_col, chosen_row);
+    }
+    Err(EarleyError::NoParse(chart.len(), false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    // The shape of an AST, for comparing them.
+    fn ast_shape(node : &ASTNode, out : &mut String)
+    {
+        out.push_str(&format!("{:?}@{}+{}", node.text, node.token_start, node.token_count));
+        if let Some(children) = &node.children
+        {
+            out.push('(');
+            for child in children { ast_shape(child, out); out.push(' '); }
+            out.push(')');
+        }
+    }
+    
+    fn parse_both_ways(grammar : &str, root : &str, input : &str) -> [Result<String, EarleyError>; 2]
+    {
+        let cg = compile_grammar(bnf_to_grammar(grammar).unwrap());
+        let mut symbols = Interner::default();
+        let tokens = tokenize(&cg, &mut symbols, input).unwrap();
+        [RightRecursionMode::AtPrediction, RightRecursionMode::AtCompletion].map(|mode|
+        {
+            let options = EarleyOptions { right_recursion : mode, ..<_>::default() };
+            let recognized = earley_recognize_with_options(&cg, root, &tokens, &options).map(|_| ());
+            let ast = earley_parse_with_options(&cg, root, &symbols, &tokens, &options);
+            assert_eq!(recognized, ast.as_ref().map(|_| ()).map_err(|e| e.clone()));
+            ast.map(|ast| { let mut shape = String::new(); ast_shape(&ast, &mut shape); shape })
+        })
+    }
+    
+    fn parses(grammar : &str, root : &str, input : &str, mode : RightRecursionMode) -> bool
+    {
+        let cg = compile_grammar(bnf_to_grammar(grammar).unwrap());
+        let mut symbols = Interner::default();
+        let tokens = tokenize(&cg, &mut symbols, input).unwrap();
+        earley_parse_with_options(&cg, root, &symbols, &tokens, &EarleyOptions { right_recursion : mode, ..<_>::default() }).is_ok()
+    }
+    
+    #[test]
+    fn tail_return_waits_for_origin_set()
+    {
+        // In the column after "a", R -> N . X predicts X while S -> "a" . R is the only parent of R so far.
+        // Q -> . R "z" only predicts R later in that column, so the tail return from X straight to S would skip Q.
+        let grammar = "S ::= \"a\" R | \"a\" W\nW ::= Q\nQ ::= R \"z\"\nR ::= N X\nN ::= #intentionally empty\nX ::= \"x\"\n";
+        assert!(parses(grammar, "S", "a x z", RightRecursionMode::AtPrediction));
+        assert!(parses(grammar, "S", "a x z", RightRecursionMode::AtCompletion));
+    }
+    
+    #[test]
+    fn missing_reductions_under_left_recursive_root()
+    {
+        // Fixing up R's tail returns walks up into S, whose origin set points back at S itself.
+        let grammar = "S ::= S \"b\" | \"a\" R\nR ::= \"a\" R | \"a\"\n";
+        assert!(parses(grammar, "S", "a a a a b", RightRecursionMode::AtPrediction));
+        assert!(parses(grammar, "S", "a a a a b", RightRecursionMode::AtCompletion));
+    }
+    
+    #[test]
+    fn ast_building_enforces_limits()
+    {
+        // Right recursion leaves fix_missing_reductions items to add, so the parse needs a bigger chart than recognizing.
+        let cg = compile_grammar(bnf_to_grammar("S ::= \"a\" S | \"a\"\n").unwrap());
+        let mut symbols = Interner::default();
+        let tokens = tokenize(&cg, &mut symbols, &"a ".repeat(100)).unwrap();
+        let with_max = |max| EarleyOptions { limits : ParseLimits { max_total_items : Some(max), ..<_>::default() }, ..<_>::default() };
+        let smallest = (1..).find(|&max| earley_recognize_with_options(&cg, "S", &tokens, &with_max(max)).is_ok()).unwrap();
+        assert_eq!(earley_parse_with_options(&cg, "S", &symbols, &tokens, &with_max(smallest)).err(), Some(EarleyError::Aborted(ParseAbort::TooManyTotalItems)));
+        assert!(earley_parse_with_options(&cg, "S", &symbols, &tokens, &with_max(smallest * 2)).is_ok());
+    }
+    
+    #[test]
+    fn right_recursion_modes_agree()
+    {
+        let readme = "program ::= B B A\nA ::= \"a\" A | \"a\" | \"b\"\nB ::= #intentionally empty\n";
+        let nested = "S ::= \"x\" S | T\nT ::= \"y\" T | \"y\" | U\nU ::= \"(\" S \")\" | \"z\"\n";
+        let left = "S ::= S \"a\" | \"a\"\n";
+        let cases = [
+            (readme, "program", "a a a a a a b"),
+            (readme, "program", "a a a b b"),
+            (readme, "program", ""),
+            (nested, "S", "x x y y ( x y y ( z ) ) "),
+            (nested, "S", "x x y ( x y"),
+            (left, "S", "a a a a"),
+            (include_str!("../grammars/c.bnf"), "translation_unit", include_str!("../grammars/c_sample.c")),
+        ];
+        for (grammar, root, input) in cases
+        {
+            let [at_prediction, at_completion] = parse_both_ways(grammar, root, input);
+            assert_eq!(at_prediction, at_completion, "{input}");
+        }
+    }
+    
+    #[test]
+    fn lattice_has_every_match_length()
+    {
+        // Name's longest match is all of "abc", so only its shorter match leaves room for the "c".
+        let cg = compile_grammar(bnf_to_grammar("S ::= Name \"c\"\nName ::= rx%[a-z]+%rx\n").unwrap());
+        let mut symbols = Interner::default();
+        let lattice = tokenize_lattice(&cg, &mut symbols, "abc").unwrap();
+        assert_eq!(lattice.edges_from(0).len(), 3);
+        assert!(earley_parse_lattice(&cg, "S", &symbols, &lattice, &EarleyOptions::default()).is_ok());
+    }
+    
+    #[test]
+    fn first_sets_filter_predictions()
+    {
+        // X's second alternation starts with "b" through B, and its third starts with "o" or "y" through the nullable O.
+        let grammar = "S ::= X\nX ::= \"a\" | B \"z\" | O \"y\"\nB ::= \"b\"\nO ::= \"o\" | #intentionally empty\n";
+        let cg = compile_grammar(bnf_to_grammar(grammar).unwrap());
+        let mut symbols = Interner::default();
+        let tokens = tokenize(&cg, &mut symbols, "a").unwrap();
+        let data = chart_fill(&cg, "S", &TokenLattice::from_tokens(&tokens), &EarleyOptions::default()).unwrap();
+        let [root, x, b, o] = ["S", "X", "B", "O"].map(|name| cg.g.by_name[name] as u32);
+        let predicted = data.chart[0].c.v.iter().filter(|item| item.rule == x).map(|item| item.alt).collect::<Vec<_>>();
+        assert_eq!(predicted, [0]);
+        assert!(!data.chart[0].c.v.iter().any(|item| item.rule == b || item.rule == o));
+        
+        let ast = earley_parse(&cg, "S", &symbols, &tokens).unwrap();
+        let mut shape = String::new();
+        ast_shape(&ast, &mut shape);
+        assert_eq!(shape, format!("Rule({root})@0+1(Rule({x})@0+1(Token({:?})@0+1 ) )", tokens[0].text));
+        // The alternations that got skipped above still get predicted where they can start.
+        for input in ["b z", "o y", "y"]
+        {
+            assert!(parses(grammar, "S", input, RightRecursionMode::default()));
+        }
+    }
+    
+    #[test]
+    fn contextual_keywords()
+    {
+        let cg = compile_grammar(bnf_to_grammar("S ::= Stmt | Stmt S\nStmt ::= \"async\" \"fn\" Name \";\" | Name \"=\" Name \";\"\nName ::= rx%[a-z]+%rx\n").unwrap());
+        let mut symbols = Interner::default();
+        let (lattice, ast) = earley_parse_contextual(&cg, "S", &mut symbols, "x = async; async fn f;", &EarleyOptions::default());
+        assert!(ast.is_ok());
+        // Only an identifier can follow "=", so the first async isn't the keyword. The second one starts a statement, so it can be.
+        let keyword = cg.g.literal_id("async");
+        let name = cg.g.terminal("Name").unwrap();
+        assert_eq!(symbols.text(lattice.tokens[2].text).as_str(), "async");
+        assert!(lattice.tokens[2].lit.is_none() && lattice.tokens[2].terms().any(|t| t == name));
+        assert_eq!(symbols.text(lattice.tokens[4].text).as_str(), "async");
+        assert!(keyword.is_some() && lattice.tokens[4].lit == keyword);
+        // Plain tokenizing doesn't know where it is, so both are the keyword.
+        let tokens = tokenize(&cg, &mut symbols, "x = async; async fn f;").unwrap();
+        assert!(tokens[2].lit == keyword && tokens[4].lit == keyword);
+    }
+    
+    #[test]
+    fn contextual_rejects_lexer_modes()
+    {
+        let cg = compile_grammar(bnf_to_grammar("S ::= \"x\"\n@mode default ::= \"x\"\n").unwrap());
+        let (_, ast) = earley_parse_contextual(&cg, "S", &mut Interner::default(), "x", &EarleyOptions::default());
+        assert!(matches!(ast, Err(EarleyError::Tokenize(_))));
+    }
+    
+    #[test]
+    fn netstring_length_uses_the_length_terminals_value()
+    {
+        // "3" is also a byte (0x33), but the length is whatever it is as digits.
+        let grammar = "netstring ::= digits \":\" payload \",\"\ndigits ::= rx%[0-9]+%rx\npayload ::= byte | payload byte\nbyte ::= 0x00-0xFF\n@length payload digits bytes\n";
+        let cg = compile_grammar(bnf_to_grammar(grammar).unwrap());
+        let parses = |input : &[u8]|
+        {
+            let mut symbols = Interner::default();
+            let tokens = tokenize_bytes(&cg, &mut symbols, input).unwrap();
+            earley_parse(&cg, "netstring", &symbols, &tokens).is_ok()
+        };
+        assert!(parses(b"3:abc,"));
+        assert!(parses(b"12:hello world!,"));
+        assert!(!parses(b"4:abc,"));
+    }
+    
+    #[test]
+    fn right_recursion_fix_stops_at_length_checks()
+    {
+        // The tail return from the innermost payload would go all the way up to netstring, past the @length check.
+        let grammar = "netstring ::= digits \":\" payload\ndigits ::= rx%[0-9]+%rx\npayload ::= byte payload | byte\nbyte ::= 0x00-0xFF\n@length payload digits\n";
+        let cg = compile_grammar(bnf_to_grammar(grammar).unwrap());
+        for mode in [RightRecursionMode::AtPrediction, RightRecursionMode::AtCompletion]
+        {
+            let options = EarleyOptions { right_recursion : mode, ..<_>::default() };
+            let parses = |input : &[u8]|
+            {
+                let mut symbols = Interner::default();
+                let tokens = tokenize_bytes(&cg, &mut symbols, input).unwrap();
+                earley_parse_with_options(&cg, "netstring", &symbols, &tokens, &options).is_ok()
+            };
+            assert!(parses(b"3:abc"));
+            assert!(!parses(b"2:abc"));
+            assert!(!parses(b"4:abc"));
+        }
+        
+        // A @length construct somewhere else doesn't turn the fix off, so the chart stays linear in the input.
+        let chart_size = |grammar : &str|
+        {
+            let cg = compile_grammar(bnf_to_grammar(grammar).unwrap());
+            let tokens = tokenize(&cg, &mut Interner::default(), &"a ".repeat(1000)).unwrap();
+            chart_fill(&cg, "S", &TokenLattice::from_tokens(&tokens), &EarleyOptions::default()).unwrap().total_items
+        };
+        let grammar = "S ::= \"a\" S | \"a\" | N \"x\" P\nN ::= rx%[0-9]+%rx\nP ::= \"p\"\n";
+        assert_eq!(chart_size(grammar), chart_size(&format!("{grammar}@length P N\n")));
+    }
+    
+    #[test]
+    fn right_recursion_fix_stops_at_restrictions()
+    {
+        // The tail return from the innermost T would go straight up to W, past the @nofollow check on W's T.
+        let grammar = "S ::= W \"y\" | W \"z\"\nW ::= \"x\" T\nT ::= \"a\" T | \"a\"\n@nofollow T \"y\"\n";
+        for mode in [RightRecursionMode::AtPrediction, RightRecursionMode::AtCompletion]
+        {
+            assert!(parses(grammar, "S", "x a a z", mode));
+            assert!(!parses(grammar, "S", "x a a y", mode));
+        }
+        
+        // A restriction somewhere else doesn't turn the fix off, so the chart stays linear in the input.
+        let chart_size = |grammar : &str|
+        {
+            let cg = compile_grammar(bnf_to_grammar(grammar).unwrap());
+            let tokens = tokenize(&cg, &mut Interner::default(), &"a ".repeat(1000)).unwrap();
+            chart_fill(&cg, "S", &TokenLattice::from_tokens(&tokens), &EarleyOptions::default()).unwrap().total_items
+        };
+        let grammar = "S ::= \"a\" S | \"a\" | N \"x\"\nN ::= \"n\"\n";
+        assert_eq!(chart_size(grammar), chart_size(&format!("{grammar}@nofollow N \"a\"\n")));
+    }
+    
+    #[test]
+    fn scannerless_restrictions()
+    {
+        let grammar = r#"@scannerless
+@layout _
+@lexical identifier idchars ws
+@nofollow identifier [a-zA-Z0-9_]
+@nofollow "if" [a-zA-Z0-9_]
+@nofollow _ [ \t\n]
+@reject identifier keyword
+S ::= "if" identifier "then" identifier "end"
+identifier ::= [a-zA-Z_] idchars
+idchars ::= idchars [a-zA-Z0-9_] |
+ws ::= [ \t\n]
+keyword ::= "if" | "then" | "end"
+_ ::= _ ws |
+"#;
+        let cg = compile_grammar(bnf_to_grammar(grammar).unwrap());
+        let parses = |input : &str|
+        {
+            let mut symbols = Interner::default();
+            let tokens = tokenize_chars(&cg, &mut symbols, input).unwrap();
+            earley_parse(&cg, "S", &symbols, &tokens).is_ok()
+        };
+        assert!(parses("if x then y end"));
+        assert!(!parses("if then then y end"));
+        assert!(!parses("ifx then y end"));
+        assert!(!parses("if xthen y end"));
+    }
+    
+    #[test]
+    fn scannerless_mixes_case_sensitivity()
+    {
+        let cg = compile_grammar(bnf_to_grammar("@scannerless\nS ::= i\"select\" | \"set\" | i\"s\"\n").unwrap());
+        let parses = |input : &str|
+        {
+            let mut symbols = Interner::default();
+            let tokens = tokenize_chars(&cg, &mut symbols, input).unwrap();
+            earley_parse(&cg, "S", &symbols, &tokens).is_ok()
+        };
+        assert!(parses("SeLeCt"));
+        assert!(parses("set"));
+        assert!(!parses("Set"));
+        assert!(parses("S"));
+    }
+    
+    #[test]
+    fn cst_view_round_trips_the_input()
+    {
+        let cg = compile_grammar(bnf_to_grammar("@comment rx%//[^\\n]*%rx\nS ::= Stmt | Stmt S\nStmt ::= Name \"=\" Name \";\"\nName ::= rx%[a-z]+%rx\n").unwrap());
+        let input = "\n// leading comment\na = b; // trailing comment\n\n\n  c=d;\n// last comment\n\n";
+        let mut symbols = Interner::default();
+        let tokens = tokenize(&cg, &mut symbols, input).unwrap();
+        let ast = earley_parse(&cg, "S", &symbols, &tokens).unwrap();
+        let root = CSTView::new(&ast, &tokens, input);
+        assert_eq!(root.full_text(), input);
+        assert_eq!(root.text(), "a = b; // trailing comment\n\n\n  c=d;");
+        
+        let mut leaves = Vec::new();
+        let mut stack = vec!(root);
+        while let Some(view) = stack.pop()
+        {
+            if view.token().is_some() { leaves.push(view.full_text()); }
+            let mut children = view.children().collect::<Vec<_>>();
+            children.reverse();
+            stack.extend(children);
+        }
+        assert_eq!(leaves.len(), tokens.len());
+        assert_eq!(leaves.concat(), input);
+    }
+    
+    #[test]
+    fn leaves_have_capture_groups()
+    {
+        let cg = compile_grammar(bnf_to_grammar("S ::= Version\nVersion ::= rx%(?<major>[0-9]+)\\.([0-9]+)%rx\n").unwrap());
+        let mut symbols = Interner::default();
+        let tokens = tokenize(&cg, &mut symbols, "12.345").unwrap();
+        let ast = earley_parse(&cg, "S", &symbols, &tokens).unwrap();
+        let leaf = &ast.children.as_ref().unwrap()[0].children.as_ref().unwrap()[0];
+        let text = leaf.text.resolve(&cg.g, &symbols);
+        assert_eq!(leaf.capture(&text, 0), Some("12.345"));
+        assert_eq!(leaf.capture(&text, 1), Some("12"));
+        assert_eq!(leaf.capture(&text, 2), Some("345"));
+        assert_eq!(leaf.capture(&text, 3), None);
+        assert_eq!(leaf.named_capture(&cg, &text, "major"), Some("12"));
+        assert_eq!(leaf.named_capture(&cg, &text, "minor"), None);
+    }
+    
+    #[test]
+    fn converts_tokens_from_other_lexers()
+    {
+        let cg = compile_grammar(bnf_to_grammar("S ::= number \"+\" number\nnumber ::= rx%[0-9]+%rx\n@convert number int\n").unwrap());
+        let (number, plus) = (cg.g.terminal("number").unwrap(), cg.g.terminal("\"+\"").unwrap());
+        let mut symbols = Interner::default();
+        let tokens = [Token::new(&mut symbols, "12", &[number]), Token::new(&mut symbols, "+", &[plus]), Token::new(&mut symbols, "30", &[number])];
+        let ast = earley_parse(&cg, "S", &symbols, &tokens).unwrap();
+        // S's children are number rules, each with the leaf in it.
+        let leaf = |i : usize| ast.children.as_ref().unwrap()[i].children.as_ref().unwrap()[0].converted.clone();
+        assert_eq!(leaf(0).as_deref(), Some(&LeafValue::Int(12)));
+        assert_eq!(leaf(2).as_deref(), Some(&LeafValue::Int(30)));
     }
-    Err((chart.len(), false))
 }
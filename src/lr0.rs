@@ -0,0 +1,536 @@
+// Alternative Earley backend: Aycock and Horspool's "Practical Earley Parsing" (2002).
+// Instead of putting individual (rule, alt, pos, start) items in the chart, this precompiles the grammar into an LR(0)
+//  automaton, and the chart holds (automaton state, start) pairs. A single automaton state stands in for a whole bunch
+//  of normal Earley items that always show up together, so the chart ends up with a lot fewer items in it.
+// Nullables are handled by the automaton itself: its states are closed over "skip this nullable rule", which is the
+//  automaton version of the nullable pre-advancement that chart_fill does.
+// IMPLEMENTATION NOTE: This backend does NOT have the right recursion hack, so right-recursive grammars like the one in the
+//  README are quadratic here, the same as in the original 1968 algorithm. Use chart_fill for those.
+
+use rustc_hash::FxBuildHasher;
+type HashMap<K, V> = std::collections::HashMap::<K, V, FxBuildHasher>;
+
+use crate::bnf::*;
+use crate::earley::{VecSet, ASTNode, ASTText, EarleyError, ParseLimits, ParseAbort, LIMIT_CHECK_INTERVAL};
+
+// An LR(0) item: (rule, alt, pos). Same meaning as the fields of the same names in StateItem.
+pub type LR0Item = (u32, u16, u16);
+
+#[derive(Debug, Default)]
+pub struct LR0State {
+    // Sorted, so that it can be binary searched and used as a key for deduplicating states.
+    pub items : Vec<LR0Item>,
+    // The "epsilon successor" of this state: everything that gets predicted by it. Its items all start in the column
+    //  that it gets added to, instead of in the column that this state's items start in, so it's a separate state.
+    pub eps : Option<usize>,
+    // Where to go after a nonterminal (by rule id) or terminal (by literal or regex id) gets matched.
+    pub rule_gotos : HashMap<usize, usize>,
+    pub lit_gotos : HashMap<usize, usize>,
+    pub regex_gotos : Vec<(usize, usize)>,
+    // Rules that have a completed item in this state.
+    pub completed : Vec<usize>,
+}
+
+impl LR0State {
+    pub fn contains(&self, item : LR0Item) -> bool { self.items.binary_search(&item).is_ok() }
+}
+
+#[derive(Debug, Default)]
+pub struct LR0Automaton {
+    pub root : usize,
+    pub start : usize,
+    pub states : Vec<LR0State>,
+    // For building the subtrees of nullables that got skipped over: which alternation to use for each nullable rule.
+    // Chosen so that following them never loops back to the same rule.
+    pub epsilon_alts : Vec<Option<u16>>,
+}
+
+fn next_term<'a>(g : &'a Grammar, item : LR0Item) -> Option<&'a MatchingTerm>
+{
+    g.points[item.0 as usize].forms[item.1 as usize].matching_terms.get(item.2 as usize)
+}
+
+// Close a set of items over skipping nullable rules, then sort and deduplicate it.
+fn close_nullables(cg : &CompiledGrammar, mut items : Vec<LR0Item>) -> Vec<LR0Item>
+{
+    let mut i = 0;
+    while i < items.len()
+    {
+        let item = items[i];
+        if let Some(MatchingTerm::Rule(id)) = next_term(&cg.g, item) && cg.nullables.contains(id)
+            && !items.contains(&(item.0, item.1, item.2 + 1))
+        {
+            items.push((item.0, item.1, item.2 + 1));
+        }
+        i += 1;
+    }
+    items.sort();
+    items.dedup();
+    items
+}
+
+// Fails for grammars with @length, @nofollow or @reject directives, which only chart_fill can check.
+pub fn lr0_automaton_build(cg : &CompiledGrammar, root_rule_name : &str) -> Result<LR0Automaton, String>
+{
+    let g = &cg.g;
+    if !g.lengths.is_empty() { return Err("The LR(0) backend doesn't support @length directives".to_string()); }
+    if !g.restrictions.is_empty() { return Err("The LR(0) backend doesn't support @nofollow or @reject directives".to_string()); }
+    let root = g.by_name[root_rule_name];
+    
+    let mut states : Vec<LR0State> = Vec::new();
+    let mut by_items : HashMap<Vec<LR0Item>, usize> = <_>::default();
+    let mut intern = |states : &mut Vec<LR0State>, items : Vec<LR0Item>| -> usize
+    {
+        if let Some(&id) = by_items.get(&items) { return id; }
+        by_items.insert(items.clone(), states.len());
+        states.push(LR0State { items, ..<_>::default() });
+        states.len() - 1
+    };
+    
+    let start_kernel = (0..g.points[root].forms.len()).map(|alt| (root as u32, alt as u16, 0)).collect();
+    let start = intern(&mut states, close_nullables(cg, start_kernel));
+    
+    // Every state gets added exactly once, so we can just walk over the list of states while it grows.
+    let mut i = 0;
+    while i < states.len()
+    {
+        // Epsilon successor: predict everything that this state's items can predict.
+        // The prediction closures from the CompiledGrammar already account for nullable prefixes.
+        let mut predicted = Vec::new();
+        for item in &states[i].items
+        {
+            if let Some(MatchingTerm::Rule(id)) = next_term(g, *item)
+            {
+                for rule in &cg.predictions[*id]
+                {
+                    for alt in 0..g.points[*rule].forms.len()
+                    {
+                        predicted.push((*rule as u32, alt as u16, 0));
+                    }
+                }
+            }
+        }
+        if !predicted.is_empty()
+        {
+            let eps = intern(&mut states, close_nullables(cg, predicted));
+            states[i].eps = Some(eps);
+        }
+        
+        // Gotos: for each symbol that comes after a dot, advance every item that's waiting on that symbol.
+        let mut kernels : Vec<(Option<TermId>, usize, Vec<LR0Item>)> = Vec::new();
+        let mut completed = Vec::new();
+        for item in &states[i].items
+        {
+            let Some(mt) = next_term(g, *item) else
+            {
+                if !completed.contains(&(item.0 as usize)) { completed.push(item.0 as usize); }
+                continue;
+            };
+            let key = (cg.term_id(mt), if let MatchingTerm::Rule(id) = mt { *id } else { 0 });
+            let progressed = (item.0, item.1, item.2 + 1);
+            match kernels.iter_mut().find(|(t, id, _)| (*t, *id) == key)
+            {
+                Some((_, _, kernel)) => kernel.push(progressed),
+                None => kernels.push((key.0, key.1, vec!(progressed))),
+            }
+        }
+        states[i].completed = completed;
+        for (term, rule_id, kernel) in kernels
+        {
+            let target = intern(&mut states, close_nullables(cg, kernel));
+            match term
+            {
+                None => { states[i].rule_gotos.insert(rule_id, target); }
+                Some(TermId::Lit(id)) => { states[i].lit_gotos.insert(id, target); }
+                Some(TermId::Regex(id)) => states[i].regex_gotos.push((id, target)),
+            }
+        }
+        i += 1;
+    }
+    
+    // Which alternation to use for skipped nullables. Keep assigning alternations that only contain
+    //  already-assigned rules until nothing changes. (This is find_nullables again, but in a dumber way.)
+    let mut epsilon_alts = vec!(None; g.points.len());
+    let mut changed = true;
+    while changed
+    {
+        changed = false;
+        for rule in &g.points
+        {
+            if epsilon_alts[rule.id].is_some() { continue; }
+            for (alt_i, alt) in rule.forms.iter().enumerate()
+            {
+                if alt.matching_terms.iter().all(|mt| matches!(mt, MatchingTerm::Rule(id) if epsilon_alts[*id].is_some()))
+                {
+                    epsilon_alts[rule.id] = Some(alt_i as u16);
+                    changed = true;
+                    break;
+                }
+            }
+        }
+    }
+    
+    Ok(LR0Automaton { root, start, states, epsilon_alts })
+}
+
+// How an item in the chart got there. Needed to rebuild the tree; LR(0) states don't say which of their items came from where.
+#[derive(Debug, Clone)]
+pub enum LR0Link {
+    // Scanned the previous token, coming from this row of the previous column.
+    Scan { pred_row : usize },
+    // A child item (in this column) completed the given rule, and we came from this row of the child's start column.
+    Complete { pred_row : usize, child_row : usize, rule : usize },
+}
+
+#[derive(Debug, Default)]
+pub struct LR0Column {
+    // (automaton state, start column)
+    pub c : VecSet<(usize, usize)>,
+    // Only filled in if links were asked for.
+    pub links : Vec<Vec<LR0Link>>,
+    // Rows waiting on each rule (by rule id). Filled in once the column is finished.
+    pub waiting : HashMap<usize, Vec<usize>>,
+}
+
+// Returns how many links the item has now.
+fn lr0_add(chart : &mut Vec<LR0Column>, col : usize, item : (usize, usize), link : Option<LR0Link>) -> usize
+{
+    if col >= chart.len() { chart.resize_with(col + 1, || <_>::default()); }
+    let column = &mut chart[col];
+    let row = column.c.insert(item);
+    if let Some(link) = link
+    {
+        if row >= column.links.len() { column.links.resize_with(row + 1, Vec::new); }
+        column.links[row].push(link);
+    }
+    column.links.get(row).map_or(0, |links| links.len())
+}
+
+// Enforces limits the same way that chart_fill does. Links stand in for reduction pointers for max_reductions,
+//  so that one only applies when with_links is set.
+pub fn lr0_chart_fill(cg : &CompiledGrammar, dfa : &LR0Automaton, tokens : &[Token], with_links : bool, limits : &ParseLimits) -> Result<Vec<LR0Column>, ParseAbort>
+{
+    limits.check_time()?;
+    let mut chart = vec!(LR0Column::default());
+    lr0_add(&mut chart, 0, (dfa.start, 0), None);
+    if let Some(eps) = dfa.states[dfa.start].eps { lr0_add(&mut chart, 0, (eps, 0), None); }
+    
+    let regex_matches = RegexMatchTable::new(cg, tokens);
+    
+    // For enforcing limits.
+    let mut finished_items = 0;
+    let mut steps = 0;
+    
+    let mut col = 0;
+    while col < chart.len()
+    {
+        let lookahead_lit = tokens.get(col).and_then(|token| token.lit);
+        
+        let mut row = 0;
+        while row < chart[col].c.len()
+        {
+            // Only this column and the next one can have grown.
+            let next_items = chart.get(col + 1).map_or(0, |column| column.c.len());
+            limits.check_sizes(chart[col].c.len().max(next_items), finished_items + chart[col].c.len() + next_items)?;
+            steps += 1;
+            if steps % LIMIT_CHECK_INTERVAL == 0 { limits.check_time()?; }
+            
+            let (state_id, start) = chart[col].c[row];
+            let state = &dfa.states[state_id];
+            
+            // Completion. Completions of zero-length items never need to be done: anything in this column that's
+            //  waiting on a rule that can complete here without consuming any tokens is already past it (that's the
+            //  nullable closure). This is the trick that makes the Aycock-Horspool version work at all.
+            if start != col
+            {
+                for rule in &state.completed
+                {
+                    let Some(parents) = chart[start].waiting.get(rule) else { continue };
+                    for pred_row in parents.clone()
+                    {
+                        let (parent_state, parent_start) = chart[start].c[pred_row];
+                        let target = dfa.states[parent_state].rule_gotos[rule];
+                        let link = with_links.then(|| LR0Link::Complete { pred_row, child_row : row, rule : *rule });
+                        let links = lr0_add(&mut chart, col, (target, parent_start), link);
+                        if limits.max_reductions.is_some_and(|max| links > max) { return Err(ParseAbort::TooManyReductions); }
+                        if let Some(eps) = dfa.states[target].eps { lr0_add(&mut chart, col, (eps, col), None); }
+                    }
+                }
+            }
+            
+            // Scan.
+            if col < tokens.len()
+            {
+                let lit_target = lookahead_lit.and_then(|lit| state.lit_gotos.get(&lit)).copied();
+                let regex_targets = state.regex_gotos.iter().filter(|(id, _)| regex_matches.matches(col, *id)).map(|x| x.1);
+                for target in lit_target.into_iter().chain(regex_targets)
+                {
+                    let link = with_links.then(|| LR0Link::Scan { pred_row : row });
+                    lr0_add(&mut chart, col + 1, (target, start), link);
+                    if let Some(eps) = dfa.states[target].eps { lr0_add(&mut chart, col + 1, (eps, col + 1), None); }
+                }
+            }
+            row += 1;
+        }
+        
+        // Column is done, so we know everything in it that might be waiting on a rule to complete.
+        let mut waiting = HashMap::<usize, Vec<usize>>::default();
+        for (row, (state_id, _)) in chart[col].c.v.iter().enumerate()
+        {
+            for rule in dfa.states[*state_id].rule_gotos.keys()
+            {
+                waiting.entry(*rule).or_default().push(row);
+            }
+        }
+        chart[col].waiting = waiting;
+        finished_items += chart[col].c.len();
+        
+        col += 1;
+    }
+    Ok(chart)
+}
+
+// Find the row in the last column that has the root rule completed, and which alternation it completed with.
+fn lr0_find_accept(cg : &CompiledGrammar, dfa : &LR0Automaton, chart : &[LR0Column]) -> Option<(usize, u16)>
+{
+    let col = chart.len() - 1;
+    for alt in 0..cg.g.points[dfa.root].forms.len()
+    {
+        let pos = cg.g.points[dfa.root].forms[alt].matching_terms.len();
+        for (row, (state_id, start)) in chart[col].c.v.iter().enumerate()
+        {
+            if *start == 0 && dfa.states[*state_id].contains((dfa.root as u32, alt as u16, pos as u16))
+            {
+                return Some((row, alt as u16));
+            }
+        }
+    }
+    None
+}
+
+#[allow(unused)]
+pub fn lr0_recognize(cg : &CompiledGrammar, dfa : &LR0Automaton, tokens : &[Token]) -> Result<u16, EarleyError>
+{
+    lr0_recognize_with_limits(cg, dfa, tokens, &ParseLimits::default())
+}
+#[allow(unused)]
+pub fn lr0_recognize_with_limits(cg : &CompiledGrammar, dfa : &LR0Automaton, tokens : &[Token], limits : &ParseLimits) -> Result<u16, EarleyError>
+{
+    let chart = lr0_chart_fill(cg, dfa, tokens, false, limits).map_err(EarleyError::Aborted)?;
+    match lr0_find_accept(cg, dfa, &chart)
+    {
+        Some(_) if chart.len() != tokens.len() + 1 => Err(EarleyError::NoParse(chart.len(), true)),
+        Some((_, alt)) => Ok(alt),
+        None => Err(EarleyError::NoParse(chart.len(), false)),
+    }
+}
+
+fn epsilon_tree(g : &Grammar, dfa : &LR0Automaton, rule : usize, col : usize) -> Result<Box<ASTNode>, EarleyError>
+{
+    let internal = || EarleyError::Internal(format!("No empty alternation to build for nullable rule {}", g.points[rule].name));
+    let alt = dfa.epsilon_alts[rule].ok_or_else(internal)? as usize;
+    let children = g.points[rule].forms[alt].matching_terms.iter().map(|mt|
+    {
+        let MatchingTerm::Rule(id) = mt else { return Err(internal()) };
+        epsilon_tree(g, dfa, *id, col)
+    }).collect::<Result<_, _>>()?;
+    Ok(Box::new(ASTNode { text : ASTText::Rule(rule as u32), children : Some(children), token_start : col, token_count : 0, captures : None, converted : None }))
+}
+
+fn term_matches(mt : &MatchingTerm, token : &Token) -> bool
+{
+    match mt {
+        MatchingTerm::Rule(_) => false,
+        MatchingTerm::TermLit(id) => token.lit == Some(*id),
+        MatchingTerm::TermRegex(regex) => token.regexes.contains(&regex.id),
+    }
+}
+
+// Same idea as build_ast_node: walk each item backwards from its end, one symbol at a time, and dispatch
+//  building children lazily instead of recursing. The difference is that chart rows are automaton states here,
+//  so at each step we have to find a link that came from a state that actually has the item we're looking at.
+// It doesn't add anything to the chart, so only the deadline and cancellation apply to it.
+pub fn lr0_build_ast(cg : &CompiledGrammar, dfa : &LR0Automaton, symbols : &Interner, tokens : &[Token], chart : &[LR0Column], limits : &ParseLimits, col : usize, row : usize, alt : u16) -> Result<Box<ASTNode>, EarleyError>
+{
+    let g = &cg.g;
+    struct ASTBuilderData {
+        children : Vec<Box<ASTNode>>, rule : usize, alt : usize,
+        col_start : usize, col : usize, row : usize, pos : usize,
+        // Where to continue from once the child that's being built is done.
+        next_col : usize, next_row : usize,
+    }
+    let new_ctx = |rule : usize, alt : usize, col : usize, row : usize| ASTBuilderData {
+        children : Vec::new(), rule, alt, col_start : col, col, row, pos : g.points[rule].forms[alt].matching_terms.len(),
+        next_col : 0, next_row : 0,
+    };
+    
+    let mut ctx = new_ctx(dfa.root, alt as usize, col, row);
+    let mut stash : Vec<ASTBuilderData> = Vec::new();
+    let mut prepared_child : Option<Box<ASTNode>> = None;
+    let mut steps = 0;
+    let mut converter = LeafConverter::default();
+    
+    loop
+    {
+        steps += 1;
+        if steps % LIMIT_CHECK_INTERVAL == 0 { limits.check_time().map_err(EarleyError::Aborted)?; }
+        
+        // Finished a node: hand it to its parent, or return it if it's the root.
+        if ctx.pos == 0
+        {
+            ctx.children.reverse();
+            let node = Box::new(ASTNode {
+                text : ASTText::Rule(ctx.rule as u32), children : Some(ctx.children),
+                token_start : ctx.col, token_count : ctx.col_start - ctx.col, captures : None, converted : None,
+            });
+            match stash.pop()
+            {
+                Some(parent) => { prepared_child = Some(node); ctx = parent; continue; }
+                None => return Ok(node),
+            }
+        }
+        
+        // Came back from building a child.
+        if let Some(child) = prepared_child.take()
+        {
+            ctx.children.push(child);
+            ctx.col = ctx.next_col;
+            ctx.row = ctx.next_row;
+            ctx.pos -= 1;
+            continue;
+        }
+        
+        let terms = &g.points[ctx.rule].forms[ctx.alt].matching_terms;
+        let mt = &terms[ctx.pos - 1];
+        let wanted = (ctx.rule as u32, ctx.alt as u16, ctx.pos as u16 - 1);
+        let links = chart[ctx.col].links.get(ctx.row).map(|x| &x[..]).unwrap_or(&[]);
+        
+        if let MatchingTerm::Rule(id) = mt
+        {
+            // A child that consumed tokens?
+            let found = links.iter().find_map(|link| match link {
+                LR0Link::Complete { pred_row, child_row, rule } if rule == id =>
+                {
+                    let child_start = chart[ctx.col].c[*child_row].1;
+                    let pred_state = chart[child_start].c[*pred_row].0;
+                    dfa.states[pred_state].contains(wanted).then_some((child_start, *pred_row, *child_row))
+                }
+                _ => None,
+            });
+            if let Some((child_start, pred_row, child_row)) = found
+            {
+                let child_state = &dfa.states[chart[ctx.col].c[child_row].0];
+                let child_alt = (0..g.points[*id].forms.len()).find(|alt|
+                    child_state.contains((*id as u32, *alt as u16, g.points[*id].forms[*alt].matching_terms.len() as u16)))
+                    .ok_or_else(|| EarleyError::Internal(format!("Completed child of rule {} at column {} has no completed item", g.points[*id].name, ctx.col)))?;
+                ctx.next_col = child_start;
+                ctx.next_row = pred_row;
+                let child_ctx = new_ctx(*id, child_alt, ctx.col, child_row);
+                stash.push(ctx);
+                ctx = child_ctx;
+                continue;
+            }
+            // If not, it has to be a nullable that got skipped over by the nullable closure.
+            if !cg.nullables.contains(id) || !dfa.states[chart[ctx.col].c[ctx.row].0].contains(wanted)
+            {
+                return Err(EarleyError::Internal(format!("No link back over rule {} at column {}", g.points[*id].name, ctx.col)));
+            }
+            ctx.children.push(epsilon_tree(g, dfa, *id, ctx.col)?);
+            ctx.pos -= 1;
+        }
+        else
+        {
+            let Some(token) = ctx.col.checked_sub(1).and_then(|i| tokens.get(i)) else
+            {
+                return Err(EarleyError::Internal(format!("No token to scan before column {}", ctx.col)));
+            };
+            let pred_row = links.iter().find_map(|link| match link {
+                LR0Link::Scan { pred_row } if dfa.states[chart[ctx.col - 1].c[*pred_row].0].contains(wanted) => Some(*pred_row),
+                _ => None,
+            }).filter(|_| term_matches(mt, token));
+            let (Some(pred_row), Some(term)) = (pred_row, cg.term_id(mt)) else
+            {
+                return Err(EarleyError::Internal(format!("No scan link back over token {} at column {}", ctx.col - 1, ctx.col)));
+            };
+            ctx.col -= 1;
+            let captures = match mt { MatchingTerm::TermRegex(regex) => token.captures_for(regex.id), _ => None };
+            let converted = converter.convert(&cg.g, symbols, token, term).map_err(|e| EarleyError::Convert(token.span, e))?;
+            ctx.children.push(Box::new(ASTNode {
+                text : ASTText::Token(token.text), children : None, token_start : ctx.col, token_count : 1, captures, converted,
+            }));
+            ctx.row = pred_row;
+            ctx.pos -= 1;
+        }
+    }
+}
+
+#[allow(unused)]
+pub fn lr0_parse(cg : &CompiledGrammar, dfa : &LR0Automaton, symbols : &Interner, tokens : &[Token]) -> Result<Box<ASTNode>, EarleyError>
+{
+    lr0_parse_with_limits(cg, dfa, symbols, tokens, &ParseLimits::default())
+}
+#[allow(unused)]
+pub fn lr0_parse_with_limits(cg : &CompiledGrammar, dfa : &LR0Automaton, symbols : &Interner, tokens : &[Token], limits : &ParseLimits) -> Result<Box<ASTNode>, EarleyError>
+{
+    let chart = lr0_chart_fill(cg, dfa, tokens, true, limits).map_err(EarleyError::Aborted)?;
+    match lr0_find_accept(cg, dfa, &chart)
+    {
+        Some(_) if chart.len() != tokens.len() + 1 => Err(EarleyError::NoParse(chart.len(), true)),
+        Some((row, alt)) => lr0_build_ast(cg, dfa, symbols, tokens, &chart, limits, chart.len() - 1, row, alt),
+        None => Err(EarleyError::NoParse(chart.len(), false)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::earley::earley_parse;
+    
+    #[test]
+    fn enforces_limits()
+    {
+        let cg = compile_grammar(bnf_to_grammar("S ::= S \"a\" | \"a\"\n").unwrap());
+        let mut symbols = Interner::default();
+        let tokens = tokenize(&cg, &mut symbols, &"a ".repeat(100)).unwrap();
+        let dfa = lr0_automaton_build(&cg, "S").unwrap();
+        let limits = ParseLimits { max_total_items : Some(50), ..<_>::default() };
+        assert_eq!(lr0_recognize_with_limits(&cg, &dfa, &tokens, &limits), Err(EarleyError::Aborted(ParseAbort::TooManyTotalItems)));
+        assert_eq!(lr0_parse_with_limits(&cg, &dfa, &symbols, &tokens, &limits).err(), Some(EarleyError::Aborted(ParseAbort::TooManyTotalItems)));
+        let cancel = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let limits = ParseLimits { cancel : Some(cancel), ..<_>::default() };
+        assert_eq!(lr0_parse_with_limits(&cg, &dfa, &symbols, &tokens, &limits).err(), Some(EarleyError::Aborted(ParseAbort::Cancelled)));
+        assert!(lr0_parse(&cg, &dfa, &symbols, &tokens).is_ok());
+    }
+    
+    #[test]
+    fn same_trees_as_earley()
+    {
+        let readme = "program ::= B B A\nA ::= \"a\" A | \"a\" | \"b\"\nB ::= #intentionally empty\n";
+        let expr = "E ::= E \"+\" T | T\nT ::= O \"x\" O | \"(\" E \")\"\nO ::= \"!\" | #intentionally empty\n";
+        for (grammar, root, input) in [(readme, "program", "a a a b"), (expr, "E", "x + ! x ! + ( x + x )"), (expr, "E", "x + +")]
+        {
+            let cg = compile_grammar(bnf_to_grammar(grammar).unwrap());
+            let mut symbols = Interner::default();
+            let tokens = tokenize(&cg, &mut symbols, input).unwrap();
+            let dfa = lr0_automaton_build(&cg, root).unwrap();
+            let lr0 = lr0_parse(&cg, &dfa, &symbols, &tokens).map(|ast| format!("{ast:?}"));
+            let earley = earley_parse(&cg, root, &symbols, &tokens).map(|ast| format!("{ast:?}"));
+            assert_eq!(lr0.is_ok(), earley.is_ok(), "{input}");
+            if lr0.is_ok() { assert_eq!(lr0, earley, "{input}"); }
+        }
+    }
+    
+    #[test]
+    fn rejects_length_directives()
+    {
+        let cg = compile_grammar(bnf_to_grammar("S ::= N \":\" P\nN ::= rx%[0-9]+%rx\nP ::= \"a\" | \"a\" P\n@length P N\n").unwrap());
+        assert!(lr0_automaton_build(&cg, "S").is_err());
+    }
+    
+    #[test]
+    fn rejects_restrictions()
+    {
+        let cg = compile_grammar(bnf_to_grammar("S ::= N | N \"a\"\nN ::= \"n\"\n@nofollow N \"a\"\n").unwrap());
+        assert!(lr0_automaton_build(&cg, "S").is_err());
+    }
+}
@@ -9,8 +9,19 @@ mod packrat;
 #[allow(unused)]
 use packrat::*;
 
+mod lr0;
+#[allow(unused)]
+use lr0::*;
+
+mod bench;
+
 // Removed the main function as it was extraneous.
 fn main() {
+    if std::env::args().nth(1).as_deref() == Some("bench")
+    {
+        bench::run_benchmarks();
+        return;
+    }
     /*
     let s = r####"
 program ::= statement_list
@@ -45,21 +56,28 @@ S ::= A
 #A ::= "a" A | "a" # packrat's preference
 A ::= A "a" | "a" # earley's preference
 "####;
-    let mut g = bnf_to_grammar(&s).unwrap();
+    let g = bnf_to_grammar(&s).unwrap();
     println!("{:#?}", &g);
+    // Do all the grammar analysis up front, so that parsing only has to pay per-input costs.
+    let g = compile_grammar(g);
     
-    let tokens = tokenize(&mut g, &"a a a a a a a a a a   \n".repeat(10000));
+    // Token text gets interned in here, not in the grammar.
+    let mut symbols = Interner::default();
+    let tokens = tokenize(&g, &mut symbols, &"a a a a a a a a a a   \n".repeat(10000));
     //println!("{:#?}", tokens);
     
     let tokens = tokens.unwrap();
     
     let start = std::time::Instant::now();
     //println!("{:#?}", earley_recognize(&g, "S", &tokens[..]));
-    let ast = earley_parse(&g, "S", &tokens[..]);
+    let ast = earley_parse(&g, "S", &symbols, &tokens[..]);
+    // RightRecursionMode::AtPrediction (the default) is the easier-to-read version of the right recursion hack.
+    //let options = EarleyOptions { right_recursion : RightRecursionMode::AtCompletion, ..<_>::default() };
+    //let ast = earley_parse_with_options(&g, "S", &symbols, &tokens[..], &options);
     println!("{}", ast.is_ok());
-    //let ast = packrat_parse(&g, "S", &tokens[..]);
+    //let ast = packrat_parse(&g, "S", &symbols, &tokens[..]);
     //println!("{}", ast.is_ok());
     println!("Time taken: {:?} under {} items", start.elapsed(), tokens.len());
     //let ast = ast.unwrap();
-    //println!("{:#?} {} {} {} {}", ast, ast.text, ast.children.as_ref().unwrap().len(), ast.token_start, ast.token_count);
+    //println!("{:#?} {} {} {} {}", ast, ast.text.resolve(&g.g, &symbols), ast.children.as_ref().unwrap().len(), ast.token_start, ast.token_count);
 }
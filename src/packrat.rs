@@ -6,13 +6,28 @@ type HashMap<K, V> = std::collections::HashMap::<K, V, FxBuildHasher>;
 type HashSet<T> = std::collections::HashSet::<T, FxBuildHasher>;
 
 use crate::bnf::*;
+use crate::earley::ASTText;
 
-#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct PackratASTNode {
-    pub text : Rc<String>,
+    pub text : ASTText,
     pub children : Option<Vec<Rc<PackratASTNode>>>,
     pub token_start : usize,
     pub token_count : usize,
+    // See ASTNode::captures.
+    pub captures : Option<std::sync::Arc<TokenCaptures>>,
+    pub converted : Option<std::sync::Arc<LeafValue>>,
+    // Where the leaf's token is and why its text didn't convert, if it didn't. Packrat parsing backtracks, so this only
+    //  becomes an error if the leaf is still in the finished tree (see packrat_parse()).
+    pub convert_error : Option<(TokenSpan, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PackratError {
+    // The input doesn't match the grammar, or the grammar uses something that this backend can't check. Why, as text.
+    NoParse(String),
+    // A leaf's text didn't convert (see Converter): where the token is, and why. (Like EarleyError::Convert.)
+    Convert(TokenSpan, String),
 }
 
 // ASTs can be deeply recursive, so we need to avoid destroying them recursively.
@@ -35,7 +50,7 @@ impl Drop for PackratASTNode {
     }
 }
 
-pub fn packrat_parse_impl(_cache : &mut HashMap<(usize, usize), Option<Rc<PackratASTNode>>>, g : &Grammar, _gp_id : usize, tokens : &[Token], _token_start : usize) -> Result<Rc<PackratASTNode>, String>
+pub fn packrat_parse_impl(_cache : &mut HashMap<(usize, usize), Option<Rc<PackratASTNode>>>, g : &Grammar, symbols : &Interner, _gp_id : usize, tokens : &[Token], _token_start : usize) -> Result<Rc<PackratASTNode>, String>
 {
     // _cache_ and _token_start are only included in the arg list for API parity with the recursive implementation
     let mut cache = HashMap::default();
@@ -64,6 +79,7 @@ pub fn packrat_parse_impl(_cache : &mut HashMap<(usize, usize), Option<Rc<Packra
     };
     
     let mut stash : Vec<ASTBuilderData> = Vec::new();
+    let mut converter = LeafConverter::default();
     
     while (ctx.i < ctx.forms.len() && ctx.j < ctx.terms.len() && ctx.token_i <= tokens.len())
         || !stash.is_empty()
@@ -72,10 +88,11 @@ pub fn packrat_parse_impl(_cache : &mut HashMap<(usize, usize), Option<Rc<Packra
         if !stash.is_empty() && !(ctx.i < ctx.forms.len() && ctx.j < ctx.terms.len() && ctx.token_i <= tokens.len())
         {
             cache.insert((ctx.gp_id, ctx.token_start), Some(Rc::new(PackratASTNode {
-                text : Rc::clone(&g.points[ctx.gp_id].name),
+                text : ASTText::Rule(ctx.gp_id as u32),
                 token_start : ctx.token_start,
                 token_count : ctx.token_i - ctx.token_start,
-                children : Some(ctx.children.clone())
+                children : Some(ctx.children.clone()),
+                captures : None, converted : None, convert_error : None,
             })));
             ctx = stash.pop().unwrap();
             continue;
@@ -120,16 +137,28 @@ pub fn packrat_parse_impl(_cache : &mut HashMap<(usize, usize), Option<Rc<Packra
                     ctx.children.push(child);
                 }
             }
-            MatchingTerm::TermLit(lit) =>
-                token_match = ctx.token_i < tokens.len() && tokens[ctx.token_i].text == *lit,
+            MatchingTerm::TermLit(id) =>
+                token_match = ctx.token_i < tokens.len() && tokens[ctx.token_i].lit == Some(*id),
             MatchingTerm::TermRegex(regex) =>
-                token_match = ctx.token_i < tokens.len() && regex.is_match(&tokens[ctx.token_i].text),
+                token_match = ctx.token_i < tokens.len() && tokens[ctx.token_i].regexes.contains(&regex.id),
         }
         if token_match
         {
+            let token = &tokens[ctx.token_i];
+            let (term, captures) = match term
+            {
+                MatchingTerm::TermRegex(regex) => (TermId::Regex(regex.id), token.captures_for(regex.id)),
+                MatchingTerm::TermLit(id) => (TermId::Lit(*id), None),
+                MatchingTerm::Rule(_) => unreachable!(),
+            };
+            let (converted, convert_error) = match converter.convert(g, symbols, token, term)
+            {
+                Ok(converted) => (converted, None),
+                Err(e) => (None, Some((token.span, e))),
+            };
             ctx.children.push(Rc::new(PackratASTNode {
-                text : Rc::clone(&tokens[ctx.token_i].text),
-                children : None, token_start : ctx.token_i, token_count : 1,
+                text : ASTText::Token(token.text),
+                children : None, token_start : ctx.token_i, token_count : 1, captures, converted, convert_error,
             }));
             ctx.token_i += 1;
         }
@@ -158,25 +187,85 @@ pub fn packrat_parse_impl(_cache : &mut HashMap<(usize, usize), Option<Rc<Packra
         }
     }
     let ret = Ok(Rc::new(PackratASTNode {
-        text : Rc::clone(&g.points[ctx.gp_id].name),
+        text : ASTText::Rule(ctx.gp_id as u32),
         token_start : ctx.token_start,
         token_count : ctx.token_i - ctx.token_start,
-        children : Some(ctx.children)
+        children : Some(ctx.children),
+        captures : None, converted : None, convert_error : None,
     }));
     ret
 }
 
+// The first leaf in the tree whose text didn't convert, if any. (Not recursive, since ASTs can be deep.)
+fn first_convert_error(root : &PackratASTNode) -> Option<(TokenSpan, String)>
+{
+    let mut stack = vec!(root);
+    while let Some(node) = stack.pop()
+    {
+        if let Some(e) = &node.convert_error { return Some(e.clone()); }
+        if let Some(children) = &node.children { stack.extend(children.iter().rev().map(|child| &**child)); }
+    }
+    None
+}
+
+// symbols is the Interner that the tokens were tokenized with, for converting leaves (see Converter).
 #[allow(unused)]
-pub fn packrat_parse(g : &Grammar, root_rule_name : &str, tokens : &[Token]) -> Result<Rc<PackratASTNode>, String>
+pub fn packrat_parse(cg : &CompiledGrammar, root_rule_name : &str, symbols : &Interner, tokens : &[Token]) -> Result<Rc<PackratASTNode>, PackratError>
 {
+    let g = &cg.g;
+    if !g.lengths.is_empty() { return Err(PackratError::NoParse("The packrat backend doesn't support @length directives".into())); }
+    if !g.restrictions.is_empty() { return Err(PackratError::NoParse("The packrat backend doesn't support @nofollow or @reject directives".into())); }
     let gp_id = g.by_name.get(root_rule_name).unwrap();
     let mut cache = HashMap::default();
-    let ret = packrat_parse_impl(&mut cache, g, *gp_id, tokens, 0);
+    let ret = packrat_parse_impl(&mut cache, g, symbols, *gp_id, tokens, 0);
     if let Ok(ret) = ret
     {
-        if ret.token_count == tokens.len() { return Ok(ret); }
+        if ret.token_count == tokens.len()
+        {
+            if let Some((span, e)) = first_convert_error(&ret) { return Err(PackratError::Convert(span, e)); }
+            return Ok(ret);
+        }
         println!("? {} {}", ret.token_count, tokens.len());
-        return Err("Failed to match entire input string".into());
+        return Err(PackratError::NoParse("Failed to match entire input string".into()));
+    }
+    ret.map_err(PackratError::NoParse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::earley::earley_parse;
+    
+    #[test]
+    fn convert_errors_only_count_in_the_finished_tree()
+    {
+        // A's n fails to convert "abc", but A backtracks, and B's regex doesn't have a converter.
+        let grammar = "S ::= A \"y\" | B \"y\"\nA ::= n \"x\"\nB ::= rx%[a-c]+%rx\nn ::= rx%[a-z]+%rx\n@convert n int\n";
+        let cg = compile_grammar(bnf_to_grammar(grammar).unwrap());
+        let mut symbols = Interner::default();
+        let tokens = tokenize(&cg, &mut symbols, "abc y").unwrap();
+        assert!(earley_parse(&cg, "S", &symbols, &tokens).is_ok());
+        assert!(packrat_parse(&cg, "S", &symbols, &tokens).is_ok());
+        let tokens = tokenize(&cg, &mut symbols, "abc x y").unwrap();
+        // The error points at the token, like EarleyError::Convert does.
+        assert!(matches!(packrat_parse(&cg, "S", &symbols, &tokens), Err(PackratError::Convert(span, _)) if (span.start, span.end) == (0, 3)));
+    }
+    
+    #[test]
+    fn rejects_length_directives()
+    {
+        let cg = compile_grammar(bnf_to_grammar("S ::= N \":\" P\nN ::= rx%[0-9]+%rx\nP ::= \"a\" | \"a\" P\n@length P N\n").unwrap());
+        let mut symbols = Interner::default();
+        let tokens = tokenize(&cg, &mut symbols, "4:a").unwrap();
+        assert!(packrat_parse(&cg, "S", &symbols, &tokens).is_err());
+    }
+    
+    #[test]
+    fn rejects_restrictions()
+    {
+        let cg = compile_grammar(bnf_to_grammar("S ::= N | N \"a\"\nN ::= \"n\"\n@nofollow N \"a\"\n").unwrap());
+        let mut symbols = Interner::default();
+        let tokens = tokenize(&cg, &mut symbols, "n a").unwrap();
+        assert!(packrat_parse(&cg, "S", &symbols, &tokens).is_err());
     }
-    ret
 }